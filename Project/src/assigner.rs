@@ -0,0 +1,379 @@
+use clap::ValueEnum;
+use log::warn;
+use serde::Deserialize;
+use std::{process::Command, time::Duration};
+
+use crate::config::Config;
+use crate::hall_request_assigner as hra;
+
+/// Strategi for å fordele etasjebestillinger mellom heisene.
+pub trait Assigner: Send {
+    fn name(&self) -> &'static str;
+    fn assign(
+        &mut self,
+        input: &hra::HallRequestsStates,
+    ) -> Result<hra::HallRequestsAssignments, String>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AssignerKind {
+    /// Den ferdigkompilerte `hall_request_assigner`-binærfilen
+    External,
+    /// Innebygd kostfunksjon, samme algoritme som binærfilen
+    CostFunction,
+    /// Gir hver bestilling til heisen som står nærmest
+    NearestCar,
+    /// Deler etasjene og retningene fast mellom heisene
+    StaticPartition,
+}
+
+/// Lager valgt strategi. Alle strategier utenom den enkleste faller tilbake til nærmeste heis dersom de feiler.
+pub fn create_assigner(config: &Config) -> Box<dyn Assigner> {
+    let travel_duration = config.assigner.travel_duration;
+    let door_open_duration = config.elevator.door_open_duration;
+
+    let assigner: Box<dyn Assigner> = match config.assigner.kind {
+        AssignerKind::External => Box::new(ExternalAssigner {
+            path: config.assigner.path.clone(),
+            travel_duration,
+            door_open_duration,
+        }),
+        AssignerKind::CostFunction => Box::new(CostFunctionAssigner {
+            travel_duration,
+            door_open_duration,
+        }),
+        AssignerKind::NearestCar => return Box::new(NearestCarAssigner),
+        AssignerKind::StaticPartition => Box::new(StaticPartitionAssigner),
+    };
+
+    Box::new(FallbackAssigner {
+        primary: assigner,
+        fallback: Box::new(NearestCarAssigner),
+    })
+}
+
+pub struct ExternalAssigner {
+    path: String,
+    travel_duration: Duration,
+    door_open_duration: Duration,
+}
+
+impl Assigner for ExternalAssigner {
+    fn name(&self) -> &'static str {
+        "external"
+    }
+    fn assign(
+        &mut self,
+        input: &hra::HallRequestsStates,
+    ) -> Result<hra::HallRequestsAssignments, String> {
+        let input_json = serde_json::to_string(input).map_err(|error| error.to_string())?;
+
+        let output = Command::new(&self.path)
+            .arg("--travelDuration")
+            .arg(self.travel_duration.as_millis().to_string())
+            .arg("--doorOpenDuration")
+            .arg(self.door_open_duration.as_millis().to_string())
+            .arg("--input")
+            .arg(&input_json)
+            .output()
+            .map_err(|error| format!("Klarte ikke starte {}: {error}", self.path))?;
+
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).to_string());
+        }
+
+        serde_json::from_slice(&output.stdout).map_err(|error| error.to_string())
+    }
+}
+
+pub struct CostFunctionAssigner {
+    travel_duration: Duration,
+    door_open_duration: Duration,
+}
+
+impl Assigner for CostFunctionAssigner {
+    fn name(&self) -> &'static str {
+        "cost-function"
+    }
+    fn assign(
+        &mut self,
+        input: &hra::HallRequestsStates,
+    ) -> Result<hra::HallRequestsAssignments, String> {
+        hra::run_hall_request_assigner(
+            input.clone(),
+            self.travel_duration,
+            self.door_open_duration,
+        )
+    }
+}
+
+pub struct NearestCarAssigner;
+
+impl Assigner for NearestCarAssigner {
+    fn name(&self) -> &'static str {
+        "nearest-car"
+    }
+    fn assign(
+        &mut self,
+        input: &hra::HallRequestsStates,
+    ) -> Result<hra::HallRequestsAssignments, String> {
+        let mut ids: Vec<&String> = input.states.keys().collect();
+        ids.sort();
+
+        let mut assignments = empty_assignments(input);
+
+        for (floor, (up, down)) in input.hall_requests.iter().enumerate() {
+            let Some(nearest) = ids
+                .iter()
+                .min_by_key(|id| (input.states[**id].floor as i32 - floor as i32).abs())
+            else {
+                break;
+            };
+
+            let assigned = &mut assignments.get_mut(*nearest).unwrap()[floor];
+            assigned.0 = *up;
+            assigned.1 = *down;
+        }
+
+        Ok(assignments)
+    }
+}
+
+/// Deler etasjene og retningene fast mellom heisene, uten å se på hvor heisene er. En bestilling havner
+/// dermed hos samme heis hver gang bestillingene fordeles på nytt, så lenge de samme heisene er i drift.
+pub struct StaticPartitionAssigner;
+
+impl Assigner for StaticPartitionAssigner {
+    fn name(&self) -> &'static str {
+        "static-partition"
+    }
+    fn assign(
+        &mut self,
+        input: &hra::HallRequestsStates,
+    ) -> Result<hra::HallRequestsAssignments, String> {
+        let mut ids: Vec<&String> = input.states.keys().collect();
+        ids.sort();
+
+        let mut assignments = empty_assignments(input);
+        if ids.is_empty() {
+            return Ok(assignments);
+        }
+
+        // Opp og ned i hver etasje får hver sin heis, fordelt nedenfra og opp
+        for (floor, (up, down)) in input.hall_requests.iter().enumerate() {
+            if *up {
+                assignments.get_mut(ids[2 * floor % ids.len()]).unwrap()[floor].0 = true;
+            }
+            if *down {
+                assignments.get_mut(ids[(2 * floor + 1) % ids.len()]).unwrap()[floor].1 = true;
+            }
+        }
+
+        Ok(assignments)
+    }
+}
+
+/// Bruker `fallback` dersom `primary` feiler, i stedet for å krasje masteren.
+pub struct FallbackAssigner {
+    primary: Box<dyn Assigner>,
+    fallback: Box<dyn Assigner>,
+}
+
+impl Assigner for FallbackAssigner {
+    fn name(&self) -> &'static str {
+        self.primary.name()
+    }
+    fn assign(
+        &mut self,
+        input: &hra::HallRequestsStates,
+    ) -> Result<hra::HallRequestsAssignments, String> {
+        self.primary.assign(input).or_else(|error| {
+            warn!(
+                "Fordeling med {} feilet, bruker {}: {error}",
+                self.primary.name(),
+                self.fallback.name()
+            );
+            self.fallback.assign(input)
+        })
+    }
+}
+
+fn empty_assignments(input: &hra::HallRequestsStates) -> hra::HallRequestsAssignments {
+    input
+        .states
+        .keys()
+        .map(|id| (id.clone(), vec![(false, false); input.hall_requests.len()]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system_state::{ElevatorState, HallRequestState, SystemState};
+
+    struct FailingAssigner;
+
+    impl Assigner for FailingAssigner {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+        fn assign(
+            &mut self,
+            _input: &hra::HallRequestsStates,
+        ) -> Result<hra::HallRequestsAssignments, String> {
+            Err("feiler alltid".to_string())
+        }
+    }
+
+    /// Heiser som står stille i hver sin etasje, uten cab-bestillinger.
+    fn input(floors: &[(&str, u8)], hall_requests: hra::HallRequests) -> hra::HallRequestsStates {
+        let states = floors
+            .iter()
+            .map(|(id, floor)| {
+                let state = hra::State {
+                    behaviour: hra::Behaviour::Idle,
+                    floor: *floor,
+                    direction: hra::Direction::Stop,
+                    cab_requests: vec![false; hall_requests.len()],
+                };
+                (id.to_string(), state)
+            })
+            .collect();
+
+        hra::HallRequestsStates {
+            hall_requests,
+            states,
+        }
+    }
+
+    #[test]
+    fn nearest_car_gets_the_call() {
+        let input = input(
+            &[("heis-1", 0), ("heis-2", 3)],
+            vec![(true, false), (false, false), (false, true), (false, false)],
+        );
+
+        let assignments = NearestCarAssigner.assign(&input).unwrap();
+        assert_eq!(assignments["heis-1"][0], (true, false));
+        assert_eq!(assignments["heis-1"][2], (false, false));
+        assert_eq!(assignments["heis-2"][2], (false, true));
+    }
+
+    #[test]
+    fn static_partition_assigns_every_call_once() {
+        let hall_requests = vec![(true, true), (true, false), (false, false), (false, false)];
+        let input = input(
+            &[("heis-1", 0), ("heis-2", 0), ("heis-3", 0)],
+            hall_requests.clone(),
+        );
+
+        let assignments = StaticPartitionAssigner.assign(&input).unwrap();
+        for (floor, (up, down)) in hall_requests.iter().enumerate() {
+            let up_count = assignments
+                .values()
+                .filter(|assigned| assigned[floor].0)
+                .count();
+            let down_count = assignments
+                .values()
+                .filter(|assigned| assigned[floor].1)
+                .count();
+            assert_eq!(up_count, *up as usize);
+            assert_eq!(down_count, *down as usize);
+        }
+
+        // Opp og ned i de to nederste etasjene er tre ulike turer, så alle heisene får én
+        assert!(assignments
+            .values()
+            .all(|assigned| assigned.iter().any(|(up, down)| *up || *down)));
+    }
+
+    fn assigned_to(state: &SystemState) -> Vec<(HallRequestState, HallRequestState)> {
+        state
+            .hall_requests
+            .iter()
+            .map(|request| (request.up.state.clone(), request.down.state.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn static_partition_keeps_assigned_calls_when_reassigning() {
+        let mut state = SystemState::new("heis-1".to_string(), 4);
+        for name in ["heis-1", "heis-2", "heis-3"] {
+            state
+                .elevators
+                .insert(name.to_string(), ElevatorState::new(4));
+        }
+        let mut assigner = StaticPartitionAssigner;
+
+        state.hall_requests[0].up.press(HallRequestState::Requested);
+        state.hall_requests[2].up.press(HallRequestState::Requested);
+        state.reassign_requests(&mut assigner);
+        let before = assigned_to(&state);
+
+        // Nye bestillinger og nye fordelinger flytter ikke bestillingene som allerede er fordelt
+        state.hall_requests[1].up.press(HallRequestState::Requested);
+        state.reassign_requests(&mut assigner);
+        state.reassign_requests(&mut assigner);
+        let after = assigned_to(&state);
+
+        assert_eq!(after[0].0, before[0].0);
+        assert_eq!(after[2].0, before[2].0);
+        assert!(matches!(after[1].0, HallRequestState::Assigned(_)));
+
+        // Bestillingene fordeles på alle heisene
+        let mut used: Vec<_> = [&after[0].0, &after[1].0, &after[2].0]
+            .into_iter()
+            .collect();
+        used.sort_by_key(|state| format!("{state:?}"));
+        used.dedup();
+        assert_eq!(used.len(), 3);
+    }
+
+    #[test]
+    fn fallback_is_used_when_primary_fails() {
+        let input = input(
+            &[("heis-1", 0), ("heis-2", 3)],
+            vec![
+                (false, false),
+                (false, false),
+                (false, false),
+                (true, false),
+            ],
+        );
+        let mut assigner = FallbackAssigner {
+            primary: Box::new(FailingAssigner),
+            fallback: Box::new(NearestCarAssigner),
+        };
+
+        assert_eq!(assigner.name(), "failing");
+        let assignments = assigner.assign(&input).unwrap();
+        assert_eq!(assignments["heis-2"][3], (true, false));
+        assert_eq!(assignments["heis-1"][3], (false, false));
+    }
+
+    #[test]
+    fn missing_external_assigner_falls_back() {
+        let input = input(
+            &[("heis-1", 1)],
+            vec![
+                (false, false),
+                (true, false),
+                (false, false),
+                (false, false),
+            ],
+        );
+        let mut external = ExternalAssigner {
+            path: "./finnes-ikke".to_string(),
+            travel_duration: Duration::from_millis(2500),
+            door_open_duration: Duration::from_secs(3),
+        };
+        assert!(external.assign(&input).is_err());
+
+        let mut assigner = FallbackAssigner {
+            primary: Box::new(external),
+            fallback: Box::new(NearestCarAssigner),
+        };
+        assert_eq!(assigner.assign(&input).unwrap()["heis-1"][1], (true, false));
+    }
+}
@@ -1,20 +1,324 @@
 use crate::system_state::SystemState;
+use log::warn;
 use serde_json::from_str;
 use serde_json::to_string_pretty;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
-pub fn load_state_from_file(file_path: &str) -> Result<SystemState, std::io::Error> {
-    let mut file = File::open(file_path)?;
-    let mut json_string = String::new();
-    file.read_to_string(&mut json_string)?;
-    let state: SystemState = from_str(&json_string).unwrap();
-    Ok(state)
+// Første linje i fila er en header med formatversjon og sjekksum for resten av fila
+const BACKUP_MAGIC: &str = "vertikale-magier-backup";
+const BACKUP_FORMAT_VERSION: u32 = 2;
+
+/// Rollen noden har når den skriver backupen. Master og slave på samme maskin skriver til hver sin fil.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Master,
+    Slave,
+}
+
+#[derive(Debug)]
+pub enum BackupError {
+    /// Det finnes ingen backup
+    Missing,
+    Io(io::Error),
+    /// Fila er skadet, f.eks. av en krasj midt i en skriving
+    Corrupt(String),
+    /// Fila er skrevet av en annen versjon av programmet
+    Incompatible(u32),
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackupError::Missing => write!(f, "fant ingen backup"),
+            BackupError::Io(error) => write!(f, "{error}"),
+            BackupError::Corrupt(reason) => write!(f, "backupen er skadet: {reason}"),
+            BackupError::Incompatible(version) => write!(
+                f,
+                "backupen har format {version}, men programmet bruker {BACKUP_FORMAT_VERSION}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+impl From<io::Error> for BackupError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => BackupError::Missing,
+            _ => BackupError::Io(error),
+        }
+    }
 }
 
-pub fn save_state_to_file(state: &SystemState, file_path: &str) -> Result<(), std::io::Error> {
+/// Hver node får sin egen fil, navngitt etter rollen og id-en til noden.
+pub fn backup_path(directory: &Path, role: Role, name: &str) -> PathBuf {
+    let role = match role {
+        Role::Master => "master",
+        Role::Slave => "slave",
+    };
+
+    directory.join(format!("{role}-{name}.json"))
+}
+
+/// Leser backupen. Er den skadet eller mangler, brukes forrige gode backup dersom den finnes.
+pub fn load_state_from_file(file_path: &Path) -> Result<SystemState, BackupError> {
+    let error = match read_backup(file_path) {
+        Ok(state) => return Ok(state),
+        Err(error) => error,
+    };
+
+    match read_backup(&previous_path(file_path)) {
+        Ok(state) => {
+            warn!(
+                "Klarte ikke lese {} ({error}), bruker forrige backup.",
+                file_path.display()
+            );
+            Ok(state)
+        }
+        Err(_) => Err(error),
+    }
+}
+
+/// Skriver backupen til en midlertidig fil som så flyttes over den gamle, slik at en krasj
+/// aldri etterlater en halvskrevet backup. Den gamle backupen tas vare på som reserve.
+pub fn save_state_to_file(state: &SystemState, file_path: &Path) -> Result<(), BackupError> {
     let json_string = to_string_pretty(state).unwrap();
-    let mut file = File::create(file_path)?;
-    file.write_all(json_string.as_bytes())?;
+    let checksum = crc32fast::hash(json_string.as_bytes());
+
+    let temporary_path = with_suffix(file_path, ".tmp");
+    {
+        let mut file = File::create(&temporary_path)?;
+        writeln!(file, "{BACKUP_MAGIC} {BACKUP_FORMAT_VERSION} {checksum:08x}")?;
+        file.write_all(json_string.as_bytes())?;
+        file.sync_all()?;
+    }
+
+    if file_path.exists() {
+        fs::rename(file_path, previous_path(file_path))?;
+    }
+    fs::rename(&temporary_path, file_path)?;
+    sync_directory(file_path)?;
+
+    Ok(())
+}
+
+/// Skriver backupen til én node, men bare når tilstanden har endret seg siden sist. Master og slave
+/// lagrer etter hver melding de får, og de fleste meldingene endrer ingenting.
+pub struct BackupWriter {
+    file_path: PathBuf,
+    saved_state: Option<SystemState>,
+}
+
+impl BackupWriter {
+    pub fn new(file_path: PathBuf) -> Self {
+        BackupWriter {
+            file_path,
+            saved_state: None,
+        }
+    }
+
+    pub fn save(&mut self, state: &SystemState) -> Result<(), BackupError> {
+        if self.saved_state.as_ref() == Some(state) {
+            return Ok(());
+        }
+
+        save_state_to_file(state, &self.file_path)?;
+        self.saved_state = Some(state.clone());
+        Ok(())
+    }
+}
+
+fn read_backup(file_path: &Path) -> Result<SystemState, BackupError> {
+    let contents = fs::read_to_string(file_path)?;
+
+    let Some((header, json_string)) = contents.split_once('\n') else {
+        return Err(BackupError::Corrupt("mangler header".to_string()));
+    };
+
+    let mut fields = header.split(' ');
+    if fields.next() != Some(BACKUP_MAGIC) {
+        return Err(BackupError::Corrupt("ukjent header".to_string()));
+    }
+
+    let version: u32 = fields
+        .next()
+        .and_then(|version| version.parse().ok())
+        .ok_or_else(|| BackupError::Corrupt("mangler formatversjon".to_string()))?;
+    if version != BACKUP_FORMAT_VERSION {
+        return Err(BackupError::Incompatible(version));
+    }
+
+    let checksum = fields
+        .next()
+        .and_then(|checksum| u32::from_str_radix(checksum, 16).ok())
+        .ok_or_else(|| BackupError::Corrupt("mangler sjekksum".to_string()))?;
+    if crc32fast::hash(json_string.as_bytes()) != checksum {
+        return Err(BackupError::Corrupt("feil sjekksum".to_string()));
+    }
+
+    from_str(json_string).map_err(|error| BackupError::Corrupt(error.to_string()))
+}
+
+fn previous_path(file_path: &Path) -> PathBuf {
+    with_suffix(file_path, ".bak")
+}
+
+fn with_suffix(file_path: &Path, suffix: &str) -> PathBuf {
+    let mut path = file_path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+// Flyttingen er ikke lagret før mappa er skrevet til disk
+#[cfg(unix)]
+fn sync_directory(file_path: &Path) -> io::Result<()> {
+    let directory = match file_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    File::open(directory)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_directory(_file_path: &Path) -> io::Result<()> {
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system_state::HallRequestState;
+
+    // Egen mappe for hver test, så testene kan kjøre samtidig
+    struct TestDirectory(PathBuf);
+
+    impl TestDirectory {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("vertikale-magier-{}-{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TestDirectory(path)
+        }
+        fn backup(&self) -> PathBuf {
+            backup_path(&self.0, Role::Slave, "heis-1")
+        }
+    }
+
+    impl Drop for TestDirectory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn state(pressed_floor: usize) -> SystemState {
+        let mut state = SystemState::new("heis-1".to_string(), 4);
+        state.hall_requests[pressed_floor].up.press(HallRequestState::Requested);
+        state
+    }
+
+    // Lagrer to ganger, så den første tilstanden ligger i reservefila
+    fn save_twice(directory: &TestDirectory) {
+        save_state_to_file(&state(0), &directory.backup()).unwrap();
+        save_state_to_file(&state(1), &directory.backup()).unwrap();
+    }
+
+    #[test]
+    fn roles_and_names_get_separate_files() {
+        let directory = Path::new("backups");
+        let paths = [
+            backup_path(directory, Role::Master, "heis-1"),
+            backup_path(directory, Role::Slave, "heis-1"),
+            backup_path(directory, Role::Slave, "heis-2"),
+        ];
+
+        for (index, path) in paths.iter().enumerate() {
+            assert!(path.starts_with(directory));
+            assert!(
+                !paths[index + 1..].contains(path),
+                "{} brukes to ganger",
+                path.display()
+            );
+        }
+    }
+
+    #[test]
+    fn loads_what_was_saved() {
+        let directory = TestDirectory::new("lagret");
+        save_twice(&directory);
+
+        assert_eq!(load_state_from_file(&directory.backup()).unwrap(), state(1));
+    }
+
+    #[test]
+    fn wrong_checksum_falls_back_to_previous_backup() {
+        let directory = TestDirectory::new("sjekksum");
+        save_twice(&directory);
+
+        // Fortsatt gyldig JSON, så det er bare sjekksummen som avslører endringen
+        let contents = fs::read_to_string(directory.backup()).unwrap();
+        let tampered = contents.replacen("\"Requested\"", "\"Inactive\"", 1);
+        assert_ne!(tampered, contents);
+        fs::write(directory.backup(), tampered).unwrap();
+        assert!(matches!(
+            read_backup(&directory.backup()),
+            Err(BackupError::Corrupt(_))
+        ));
+
+        assert_eq!(load_state_from_file(&directory.backup()).unwrap(), state(0));
+    }
+
+    #[test]
+    fn truncated_backup_falls_back_to_previous_backup() {
+        let directory = TestDirectory::new("avkuttet");
+        save_twice(&directory);
+
+        let contents = fs::read(directory.backup()).unwrap();
+        fs::write(directory.backup(), &contents[..contents.len() / 2]).unwrap();
+
+        assert_eq!(load_state_from_file(&directory.backup()).unwrap(), state(0));
+    }
+
+    #[test]
+    fn writer_only_saves_changed_state() {
+        let directory = TestDirectory::new("bare-endringer");
+        let mut writer = BackupWriter::new(directory.backup());
+
+        writer.save(&state(0)).unwrap();
+        assert_eq!(load_state_from_file(&directory.backup()).unwrap(), state(0));
+
+        // Fila skrives ikke på nytt når tilstanden er den samme
+        fs::remove_file(directory.backup()).unwrap();
+        writer.save(&state(0)).unwrap();
+        assert!(!directory.backup().exists());
+
+        writer.save(&state(1)).unwrap();
+        assert_eq!(load_state_from_file(&directory.backup()).unwrap(), state(1));
+    }
+
+    #[test]
+    fn corrupt_backup_without_previous_is_an_error() {
+        let directory = TestDirectory::new("uten-reserve");
+        assert!(matches!(
+            load_state_from_file(&directory.backup()),
+            Err(BackupError::Missing)
+        ));
+
+        save_state_to_file(&state(0), &directory.backup()).unwrap();
+        fs::write(
+            directory.backup(),
+            format!("{BACKUP_MAGIC} {BACKUP_FORMAT_VERSION} 00000000\n{{}}"),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            load_state_from_file(&directory.backup()),
+            Err(BackupError::Corrupt(_))
+        ));
+    }
+}
@@ -0,0 +1,108 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use crate::elevator_controller::{Direction, State};
+use crate::network::codec::Codec;
+use crate::network::protocol::{Envelope, Message};
+use crate::state_sync::{Delta, HallDirection, StateChange, StateUpdate};
+use crate::system_state::{ElevatorState, HallCall, HallRequestState, SystemState};
+
+const ITERATIONS: u32 = 10_000;
+const ELEVATORS: [&str; 3] = ["heis-1", "heis-2", "heis-3"];
+
+/// Måler hvor store meldingene blir og hvor lang tid koding og dekoding tar med hver codec.
+/// Meldingene er pakket inn slik de sendes, og tilstanden ligner den i et system med tre heiser.
+pub fn run_codec_benchmark(number_of_floors: u8) {
+    let messages = [
+        ("Hele tilstanden", StateUpdate::Snapshot(example_state(number_of_floors))),
+        ("Endringer", StateUpdate::Delta(example_delta())),
+    ];
+
+    println!("{:<16} {:<8} {:>7} {:>12} {:>12}", "Melding", "Codec", "Bytes", "Koding", "Dekoding");
+
+    for (name, update) in messages {
+        let envelope = Envelope::new(ELEVATORS[0].to_string(), 1, Message::StateUpdate(update));
+
+        for codec in [Codec::Json, Codec::Bincode] {
+            let bytes = codec.encode(&envelope).unwrap();
+
+            let encode_time = time_per_iteration(|| {
+                black_box(codec.encode(black_box(&envelope)).unwrap());
+            });
+            let decode_time = time_per_iteration(|| {
+                black_box(codec.decode::<Envelope<StateUpdate>>(black_box(&bytes)).unwrap());
+            });
+
+            println!(
+                "{name:<16} {codec:<8} {:>7} {:>9.2} µs {:>9.2} µs",
+                bytes.len(),
+                encode_time.as_secs_f64() * 1e6,
+                decode_time.as_secs_f64() * 1e6
+            );
+        }
+    }
+}
+
+fn time_per_iteration(mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn example_state(number_of_floors: u8) -> SystemState {
+    let mut state = SystemState::new(ELEVATORS[0].to_string(), number_of_floors);
+
+    for (index, name) in ELEVATORS.iter().enumerate() {
+        let mut elevator = ElevatorState::new(number_of_floors);
+        elevator.floor = index as u8 % number_of_floors;
+        elevator.state = State::Moving;
+        elevator.direction = Direction::Up;
+        elevator.cab_requests[number_of_floors as usize - 1] = true;
+
+        state.elevators.insert(name.to_string(), elevator);
+        state.version.increment(name);
+        state.version.increment(&format!("{name}:master"));
+    }
+
+    // Halvparten av etasjebestillingene er aktive, noen fordelt og noen ikke
+    for (floor, request) in state.hall_requests.iter_mut().enumerate() {
+        if floor % 2 == 0 {
+            request.up.press(HallRequestState::Assigned(ELEVATORS[floor % ELEVATORS.len()].to_string()));
+        } else {
+            request.down.press(HallRequestState::Requested);
+        }
+    }
+
+    state
+}
+
+// Det en heis typisk sender når den stopper i en etasje
+fn example_delta() -> Delta {
+    Delta {
+        origin: ELEVATORS[0].to_string(),
+        version: 1,
+        changes: vec![
+            StateChange::Elevator {
+                name: ELEVATORS[0].to_string(),
+                direction: Direction::Up,
+                state: State::DoorOpen,
+                floor: 1,
+            },
+            StateChange::CabRequest {
+                name: ELEVATORS[0].to_string(),
+                floor: 1,
+                active: false,
+            },
+            StateChange::HallRequest {
+                floor: 1,
+                direction: HallDirection::Up,
+                call: HallCall {
+                    state: HallRequestState::Inactive,
+                    version: 2,
+                },
+            },
+        ],
+    }
+}
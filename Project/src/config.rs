@@ -1,4 +1,253 @@
-use std::time::Duration;
+use serde::Deserialize;
+use std::{
+    fs,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-pub const NUMBER_OF_FLOORS: usize = 4;
-pub const DOOR_OPEN_DURATION: Duration = Duration::from_secs(3);
+use crate::assigner::AssignerKind;
+use crate::network::codec::Codec;
+use crate::network::socket::QueuePolicy;
+
+/// Innstillinger for hele systemet. Leses fra en TOML-fil der alle felt er valgfrie,
+/// og varigheter oppgis i millisekunder. Se `config.example.toml`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub number_of_floors: u8,
+    /// Porten til heisserveren (simulator eller fysisk heis)
+    pub elevator_port: u16,
+    /// Mappa backupene skrives til. Hver rolle og node får sin egen fil her.
+    pub backup_directory: PathBuf,
+    pub elevator: ElevatorConfig,
+    pub network: NetworkConfig,
+    pub assigner: AssignerConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ElevatorConfig {
+    #[serde(rename = "door_open_duration_ms", with = "milliseconds")]
+    pub door_open_duration: Duration,
+    /// Hvor lenge heisen kan kjøre uten å nå en etasje før den regnes som ute av drift
+    #[serde(rename = "travel_timeout_ms", with = "milliseconds")]
+    pub travel_timeout: Duration,
+    /// Hvor lenge døra kan være obstruert før heisen regnes som ute av drift
+    #[serde(rename = "obstruction_timeout_ms", with = "milliseconds")]
+    pub obstruction_timeout: Duration,
+    /// Hvor lenge heisen leter etter en etasje i én retning ved oppstart før den snur
+    #[serde(rename = "homing_timeout_ms", with = "milliseconds")]
+    pub homing_timeout: Duration,
+    /// Hvor ofte knapper og sensorer leses
+    #[serde(rename = "poll_period_ms", with = "milliseconds")]
+    pub poll_period: Duration,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub advertising_ip: Ipv4Addr,
+    pub advertising_port: u16,
+    #[serde(rename = "advertising_interval_ms", with = "milliseconds")]
+    pub advertising_interval: Duration,
+    /// Hvor ofte valget ser etter noder som har sluttet å annonsere
+    #[serde(rename = "heartbeat_interval_ms", with = "milliseconds")]
+    pub heartbeat_interval: Duration,
+    /// Hvor lenge en node kan være stille før den regnes som død
+    #[serde(rename = "peer_timeout_ms", with = "milliseconds")]
+    pub peer_timeout: Duration,
+    /// Hvor ofte master og slaver sender hele tilstanden i tillegg til endringene
+    #[serde(rename = "snapshot_interval_ms", with = "milliseconds")]
+    pub snapshot_interval: Duration,
+    /// Hva slaven gjør med meldinger til master mens den ikke er tilkoblet. Slaven sender alltid hele
+    /// tilstanden sin når den kobler til, så master får med seg endringene uansett. Med `keep` sendes
+    /// endringene i tillegg etter tilstanden, og med `drop` kastes de.
+    pub queue_policy: QueuePolicy,
+    /// Formatet meldingene sendes i. Alle noder i systemet må bruke samme format.
+    pub codec: Codec,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AssignerConfig {
+    pub kind: AssignerKind,
+    /// Stien til `hall_request_assigner`-binærfilen som brukes av `external`
+    pub path: String,
+    /// Tiden fordeleren regner med at heisen bruker mellom to etasjer
+    #[serde(rename = "travel_duration_ms", with = "milliseconds")]
+    pub travel_duration: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            number_of_floors: 4,
+            elevator_port: 15657,
+            backup_directory: PathBuf::from("."),
+            elevator: Default::default(),
+            network: Default::default(),
+            assigner: Default::default(),
+        }
+    }
+}
+
+impl Default for ElevatorConfig {
+    fn default() -> Self {
+        ElevatorConfig {
+            door_open_duration: Duration::from_secs(3),
+            travel_timeout: Duration::from_secs(5),
+            obstruction_timeout: Duration::from_secs(10),
+            homing_timeout: Duration::from_secs(5),
+            poll_period: Duration::from_millis(25),
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            // Use port 52052 and 239.0.0.52 for group 52 <3
+            advertising_ip: Ipv4Addr::new(239, 0, 0, 52),
+            advertising_port: 52052,
+            advertising_interval: Duration::from_secs(1),
+            heartbeat_interval: Duration::from_secs(1),
+            peer_timeout: Duration::from_secs(3),
+            snapshot_interval: Duration::from_secs(5),
+            queue_policy: QueuePolicy::Drop,
+            codec: Codec::Bincode,
+        }
+    }
+}
+
+impl Default for AssignerConfig {
+    fn default() -> Self {
+        AssignerConfig {
+            kind: AssignerKind::CostFunction,
+            path: "./hall_request_assigner".to_string(),
+            travel_duration: Duration::from_millis(2500),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|error| format!("Klarte ikke lese {}: {error}", path.display()))?;
+
+        toml::from_str(&text)
+            .map_err(|error| format!("Ugyldig konfigurasjon i {}: {error}", path.display()))
+    }
+
+    /// Sjekker at innstillingene henger sammen, slik at feil oppdages ved oppstart og ikke midt i driften.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.number_of_floors < 2 {
+            return Err("number_of_floors må være minst 2".to_string());
+        }
+
+        let durations = [
+            ("elevator.door_open_duration_ms", self.elevator.door_open_duration),
+            ("elevator.travel_timeout_ms", self.elevator.travel_timeout),
+            ("elevator.obstruction_timeout_ms", self.elevator.obstruction_timeout),
+            ("elevator.homing_timeout_ms", self.elevator.homing_timeout),
+            ("elevator.poll_period_ms", self.elevator.poll_period),
+            ("network.advertising_interval_ms", self.network.advertising_interval),
+            ("network.heartbeat_interval_ms", self.network.heartbeat_interval),
+            ("network.peer_timeout_ms", self.network.peer_timeout),
+            ("network.snapshot_interval_ms", self.network.snapshot_interval),
+            ("assigner.travel_duration_ms", self.assigner.travel_duration),
+        ];
+        for (name, duration) in durations {
+            if duration.is_zero() {
+                return Err(format!("{name} kan ikke være 0"));
+            }
+        }
+
+        if !self.network.advertising_ip.is_multicast() {
+            return Err(format!(
+                "network.advertising_ip ({}) må være en multicast-adresse",
+                self.network.advertising_ip
+            ));
+        }
+        if self.network.peer_timeout <= self.network.advertising_interval {
+            return Err(
+                "network.peer_timeout_ms må være lengre enn network.advertising_interval_ms"
+                    .to_string(),
+            );
+        }
+        if self.backup_directory.as_os_str().is_empty() {
+            return Err("backup_directory kan ikke være tom".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+mod milliseconds {
+    use serde::{Deserialize, Deserializer};
+    use std::time::Duration;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_config_has_the_default_values() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("config.example.toml");
+        let config = Config::load(&path).unwrap();
+        let defaults = Config::default();
+
+        config.validate().unwrap();
+        assert_eq!(config.number_of_floors, defaults.number_of_floors);
+        assert_eq!(config.backup_directory, defaults.backup_directory);
+        assert_eq!(
+            config.elevator.obstruction_timeout,
+            defaults.elevator.obstruction_timeout
+        );
+        assert_eq!(config.network.peer_timeout, defaults.network.peer_timeout);
+        assert_eq!(config.network.queue_policy, defaults.network.queue_policy);
+        assert_eq!(config.assigner.kind, defaults.assigner.kind);
+    }
+
+    #[test]
+    fn missing_fields_get_default_values() {
+        let config: Config = toml::from_str(
+            "number_of_floors = 6\n[elevator]\ndoor_open_duration_ms = 1500\n[network]\nqueue_policy = \"keep\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.number_of_floors, 6);
+        assert_eq!(
+            config.elevator.door_open_duration,
+            Duration::from_millis(1500)
+        );
+        assert_eq!(config.elevator.travel_timeout, Duration::from_secs(5));
+        assert_eq!(config.network.queue_policy, QueuePolicy::Keep);
+        assert_eq!(config.assigner.kind, AssignerKind::CostFunction);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(toml::from_str::<Config>("[elevator]\ndoor_open_duration = 1500\n").is_err());
+    }
+
+    #[test]
+    fn inconsistent_config_is_rejected() {
+        let mut config = Config::default();
+        config.network.peer_timeout = config.network.advertising_interval;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.elevator.door_open_duration = Duration::ZERO;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.network.advertising_ip = Ipv4Addr::LOCALHOST;
+        assert!(config.validate().is_err());
+    }
+}
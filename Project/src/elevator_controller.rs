@@ -1,11 +1,10 @@
 use crossbeam_channel as cbc;
-use driver_rust::elevio;
-use log::debug;
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 
-use crate::config::DOOR_OPEN_DURATION;
+use crate::config::{Config, ElevatorConfig};
+use crate::elevator_io::ElevatorIo;
 use crate::timer::Timer;
-use crate::{config::NUMBER_OF_FLOORS, inputs};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum State {
@@ -29,7 +28,7 @@ pub struct Request {
     pub cab: bool,
 }
 
-pub type Requests = [Request; NUMBER_OF_FLOORS];
+pub type Requests = Vec<Request>;
 
 pub struct ElevatorEvent {
     pub direction: Direction,
@@ -38,30 +37,45 @@ pub struct ElevatorEvent {
 }
 
 #[derive(Debug, Clone)]
-struct ElevatorController<'e> {
-    elevio_driver: &'e elevio::elev::Elevator,
+struct ElevatorController<'e, E: ElevatorIo> {
+    elevator_io: &'e E,
     door_timer: Timer,
+    homing_timer: Timer,
+    travel_timer: Timer,
+    obstruction_timer: Timer,
     fsm_state: State,
     direction: Direction,
     obstruction: bool,
+    stop_button: bool,
+    motor_stalled: bool,
+    prolonged_obstruction: bool,
     last_floor: Option<u8>,
     requests: Requests,
 }
 
-impl<'e> ElevatorController<'e> {
-    fn new(elevio_driver: &'e elevio::elev::Elevator) -> Self {
+impl<'e, E: ElevatorIo> ElevatorController<'e, E> {
+    fn new(elevator_io: &'e E, config: &ElevatorConfig, number_of_floors: u8) -> Self {
         Self {
-            elevio_driver,
-            door_timer: Timer::init(DOOR_OPEN_DURATION),
+            elevator_io,
+            door_timer: Timer::init(config.door_open_duration),
+            homing_timer: Timer::init(config.homing_timeout),
+            travel_timer: Timer::init(config.travel_timeout),
+            obstruction_timer: Timer::init(config.obstruction_timeout),
             fsm_state: State::Idle,
             direction: Direction::Stopped,
-            obstruction: true, // Assume worst until we hear otherwise from driver
-            last_floor: Some(0),
-            requests: [Request {
-                cab: false,
-                hall_up: false,
-                hall_down: false,
-            }; NUMBER_OF_FLOORS],
+            obstruction: elevator_io.obstruction(), // Polleren melder bare endringer
+            stop_button: false,
+            motor_stalled: false,
+            prolonged_obstruction: false,
+            last_floor: None, // Ukjent til heisen har funnet en etasje
+            requests: vec![
+                Request {
+                    cab: false,
+                    hall_up: false,
+                    hall_down: false,
+                };
+                number_of_floors as usize
+            ],
         }
     }
     fn requests_below(&self) -> bool {
@@ -98,7 +112,7 @@ impl<'e> ElevatorController<'e> {
     fn next_direction(&self) -> (Direction, State) {
         match self.direction {
             Direction::Up => {
-                return if self.requests_above() {
+                if self.requests_above() {
                     (Direction::Up, State::Moving)
                 } else if self.requests_here(Some(Direction::Up)) {
                     (Direction::Up, State::DoorOpen)
@@ -111,7 +125,7 @@ impl<'e> ElevatorController<'e> {
                 }
             }
             Direction::Down => {
-                return if self.requests_below() {
+                if self.requests_below() {
                     (Direction::Down, State::Moving)
                 } else if self.requests_here(Some(Direction::Down)) {
                     (Direction::Down, State::DoorOpen)
@@ -124,7 +138,7 @@ impl<'e> ElevatorController<'e> {
                 }
             }
             Direction::Stopped => {
-                return if self.requests_here(None) {
+                if self.requests_here(None) {
                     (Direction::Stopped, State::DoorOpen)
                 } else if self.requests_above() {
                     (Direction::Up, State::Moving)
@@ -144,16 +158,12 @@ impl<'e> ElevatorController<'e> {
 
         match self.direction {
             Direction::Down => {
-                return self.requests[floor].hall_down
-                    || self.requests[floor].cab
-                    || !self.requests_below()
+                self.requests[floor].hall_down || self.requests[floor].cab || !self.requests_below()
             }
             Direction::Up => {
-                return self.requests[floor].hall_up
-                    || self.requests[floor].cab
-                    || !self.requests_above()
+                self.requests[floor].hall_up || self.requests[floor].cab || !self.requests_above()
             }
-            _ => return true,
+            _ => true,
         }
     }
     fn transision_to_moving(&mut self) {
@@ -162,115 +172,293 @@ impl<'e> ElevatorController<'e> {
 
         match self.direction {
             Direction::Up => {
-                self.elevio_driver.motor_direction(elevio::elev::DIRN_UP);
+                self.elevator_io.motor_direction(Direction::Up);
                 self.direction = Direction::Up;
             }
             Direction::Down => {
-                self.elevio_driver.motor_direction(elevio::elev::DIRN_DOWN);
+                self.elevator_io.motor_direction(Direction::Down);
                 self.direction = Direction::Down;
             }
             _ => panic!("Prøvde å bytte til tilstand \"kjører\" uten at heisen trenger å kjøre."),
         }
+
+        // Heisen må nå en etasje innen en viss tid, ellers har motoren trolig mistet strømmen
+        self.travel_timer.restart();
     }
     fn transision_to_door_open(&mut self) {
         debug!("Bytter til tilstand \"dør åpen\".");
         self.fsm_state = State::DoorOpen;
 
-        self.elevio_driver.motor_direction(elevio::elev::DIRN_STOP);
-        self.elevio_driver.door_light(true);
+        self.elevator_io.motor_direction(Direction::Stopped);
+        self.travel_timer.stop();
+        self.elevator_io.door_light(true);
 
         debug!("Dør åpen.");
         self.door_timer.start();
+
+        if self.obstruction {
+            self.obstruction_timer.start();
+        }
     }
     fn transision_to_idle(&mut self) {
         debug!("Bytter til tilstand \"inaktiv\".");
         self.fsm_state = State::Idle;
     }
+    // Kjører til nærmeste etasje dersom heisen starter mellom to etasjer
+    fn start_homing(&mut self) {
+        if let Some(floor) = self.elevator_io.floor_sensor() {
+            self.finish_homing(floor);
+            return;
+        }
+
+        info!("Heisen står mellom to etasjer, kjører ned til nærmeste etasje.");
+        self.direction = Direction::Down;
+        self.transision_to_moving();
+        self.homing_timer.start();
+    }
+    fn finish_homing(&mut self, floor: u8) {
+        info!("Heisen fant etasje {} og er klar.", floor + 1);
+        self.last_floor = Some(floor);
+        self.elevator_io.floor_indicator(floor);
+
+        self.elevator_io.motor_direction(Direction::Stopped);
+        self.travel_timer.stop();
+        self.motor_stalled = false;
+        self.direction = Direction::Stopped;
+        self.transision_to_idle();
+
+        // Bestillinger mottatt under oppstarten tas nå
+        self.serve_requests();
+    }
+    // Starter på neste bestilling dersom heisen er ledig
+    fn serve_requests(&mut self) {
+        if self.fsm_state != State::Idle {
+            return;
+        }
+
+        let (next_direction, next_state) = self.next_direction();
+        self.direction = next_direction;
+
+        match next_state {
+            State::DoorOpen => self.transision_to_door_open(),
+            State::Moving => self.transision_to_moving(),
+            _ => {}
+        }
+    }
+    // En heis som har satt seg fast eller ikke får lukket døra meldes ute av drift,
+    // selv om den fortsatt prøver å betjene bestillingene sine
+    fn reported_state(&self) -> State {
+        if self.motor_stalled || self.prolonged_obstruction {
+            State::OutOfOrder
+        } else {
+            self.fsm_state
+        }
+    }
+    // Heisen rapporterer ikke tilstanden sin før den vet hvilken etasje den er i
+    fn report(&self, elevator_event_tx: &cbc::Sender<ElevatorEvent>) {
+        let Some(floor) = self.last_floor else {
+            return;
+        };
+
+        elevator_event_tx
+            .send(ElevatorEvent {
+                direction: self.direction,
+                state: self.reported_state(),
+                floor,
+            })
+            .unwrap();
+    }
+    fn transision_to_stopped(&mut self) {
+        debug!("Bytter til tilstand \"ute av drift\" (nødstopp).");
+        self.fsm_state = State::OutOfOrder;
+
+        self.elevator_io.motor_direction(Direction::Stopped);
+        self.travel_timer.stop();
+        self.elevator_io.stop_button_light(true);
+
+        // Døra kan bare åpnes når heisen står i en etasje
+        if self.elevator_io.floor_sensor().is_some() {
+            self.elevator_io.door_light(true);
+            debug!("Dør åpen.");
+        }
+    }
+    fn resume_after_stop(&mut self) {
+        debug!("Nødstopp avsluttet.");
+        self.elevator_io.stop_button_light(false);
+
+        // I en etasje står døra åpen, og lukkes som vanlig når dørtimeren går ut
+        if self.elevator_io.floor_sensor().is_some() {
+            self.transision_to_door_open();
+            return;
+        }
+
+        // Mellom to etasjer kjører heisen videre til neste etasje før den tar bestillinger igjen
+        if self.direction == Direction::Stopped {
+            self.direction = Direction::Down;
+        }
+        self.transision_to_moving();
+
+        if self.last_floor.is_none() {
+            self.homing_timer.start();
+        }
+    }
 }
 
-pub fn controller_loop(
-    elevio_elevator: &elevio::elev::Elevator,
+pub fn controller_loop<E: ElevatorIo>(
+    elevator_io: &E,
+    config: &Config,
     command_channel_rx: cbc::Receiver<Requests>,
     elevator_event_tx: cbc::Sender<ElevatorEvent>,
 ) {
-    let rx_channels = inputs::get_input_channels(&elevio_elevator);
-    let mut controller = ElevatorController::new(&elevio_elevator);
+    let timings = &config.elevator;
+    let rx_channels = elevator_io.input_channels(timings.poll_period);
+    let mut controller = ElevatorController::new(elevator_io, timings, config.number_of_floors);
+
+    controller.start_homing();
+    controller.report(&elevator_event_tx);
 
     loop {
         cbc::select! {
             recv(command_channel_rx) -> command => {
-                let requests = command.unwrap();
+                // Kanalen lukkes når heisen skal avsluttes
+                let Ok(requests) = command else {
+                    break;
+                };
                 debug!("Recieved new requests: {:?}", requests);
 
+                if requests.len() != controller.requests.len() {
+                    warn!("Fikk bestillinger for {} etasjer, men heisen har {}.", requests.len(), controller.requests.len());
+                    continue;
+                }
+
                 controller.requests = requests;
 
+                // Under oppstart og nødstopp huskes bestillingene til heisen er klar
                 if controller.fsm_state != State::Idle {
                     continue;
                 }
 
-                let (next_direction, next_state) = controller.next_direction();
-                controller.direction = next_direction;
-
-                match next_state {
-                    State::DoorOpen => controller.transision_to_door_open(),
-                    State::Moving => controller.transision_to_moving(),
-                    _ => {},
-                }
+                controller.serve_requests();
 
                 if controller.fsm_state != State::Idle {
-                    elevator_event_tx.send(ElevatorEvent {
-                        direction: controller.direction,
-                        state: controller.fsm_state,
-                        floor: controller.last_floor.unwrap(),
-                    }).unwrap();
+                    controller.report(&elevator_event_tx);
                 }
             },
             recv(rx_channels.floor_sensor_rx) -> floor => {
                 let floor = floor.unwrap();
                 debug!("Detekterte etasje: {floor}");
 
-                elevio_elevator.floor_indicator(floor); // TODO: Bruk sync lights her kanskje?
+                if controller.last_floor.is_none() && !controller.stop_button {
+                    controller.finish_homing(floor);
+                    controller.report(&elevator_event_tx);
+                    continue;
+                }
+
+                elevator_io.floor_indicator(floor); // TODO: Bruk sync lights her kanskje?
                 controller.last_floor = Some(floor);
 
                 if controller.fsm_state != State::Moving {
                     continue;
                 }
 
+                if controller.motor_stalled {
+                    info!("Heisen kjører igjen og er tilbake i drift.");
+                    controller.motor_stalled = false;
+                }
+
                 if controller.should_stop() {
                     controller.transision_to_door_open();
+                } else {
+                    controller.travel_timer.restart();
                 }
 
-                elevator_event_tx.send(ElevatorEvent {
-                    direction: controller.direction,
-                    state: controller.fsm_state,
-                    floor: controller.last_floor.unwrap(),
-                }).unwrap();
+                controller.report(&elevator_event_tx);
             },
             recv(rx_channels.stop_button_rx) -> stop_button => {
                 let stop_button = stop_button.unwrap();
                 debug!("Detekterte stopknapp: {:}", stop_button);
 
-                elevio_elevator.motor_direction(elevio::elev::DIRN_STOP);
+                controller.stop_button = stop_button;
+
+                if stop_button {
+                    controller.transision_to_stopped();
+                } else {
+                    controller.resume_after_stop();
+                }
 
-                controller.fsm_state = State::OutOfOrder;
+                controller.report(&elevator_event_tx);
             },
             recv(rx_channels.obstruction_rx) -> obstruction_switch => {
                 controller.obstruction = obstruction_switch.unwrap();
                 debug!("Detekterte obstruksjon: {:}", controller.obstruction);
+
+                // Tiden døra har vært obstruert regnes fra obstruksjonen oppdages
+                if controller.obstruction {
+                    if controller.fsm_state == State::DoorOpen {
+                        controller.obstruction_timer.start();
+                    }
+                    continue;
+                }
+
+                controller.obstruction_timer.stop();
+
+                if controller.prolonged_obstruction {
+                    info!("Døra er ikke lenger obstruert, heisen er tilbake i drift.");
+                    controller.prolonged_obstruction = false;
+                    controller.report(&elevator_event_tx);
+                }
+            },
+            recv(controller.obstruction_timer.timeout_channel()) -> _ => {
+                if !controller.obstruction || controller.fsm_state != State::DoorOpen || controller.prolonged_obstruction {
+                    continue;
+                }
+
+                warn!("Døra har vært obstruert i {:?}, melder heisen ute av drift.", timings.obstruction_timeout);
+                controller.prolonged_obstruction = true;
+                controller.report(&elevator_event_tx);
+            },
+            recv(controller.homing_timer.timeout_channel()) -> _ => {
+                if controller.last_floor.is_some() || controller.stop_button {
+                    continue;
+                }
+
+                // Fant ingen etasje i denne retningen, så heisen må ha stått i enden av sjakta
+                warn!("Fant ingen etasje, snur.");
+                controller.direction = match controller.direction {
+                    Direction::Down => Direction::Up,
+                    _ => Direction::Down,
+                };
+                controller.transision_to_moving();
+                controller.homing_timer.start();
+            },
+            recv(controller.travel_timer.timeout_channel()) -> _ => {
+                // Under oppstarten er det homing-timeren som passer på at heisen finner en etasje
+                if controller.fsm_state != State::Moving || controller.motor_stalled || controller.last_floor.is_none() {
+                    continue;
+                }
+
+                warn!("Heisen har ikke nådd en etasje på {:?}, melder den ute av drift.", timings.travel_timeout);
+                controller.motor_stalled = true;
+                controller.report(&elevator_event_tx);
             },
             recv(controller.door_timer.timeout_channel()) -> _ => {
+                // Døra står åpen så lenge nødstoppen er inne
+                if controller.stop_button {
+                    continue;
+                }
+
                 if controller.obstruction {
                     debug!("Dør obstruert!");
                     controller.door_timer.start();
                     continue;
                 }
 
-                elevio_elevator.door_light(false);
+                elevator_io.door_light(false);
                 debug!("Dør lukket.");
 
                 let (next_direction, next_state) = controller.next_direction();
                 controller.direction = next_direction;
-                dbg!(next_direction);
+                debug!("Neste retning: {next_direction:?}");
 
                 match next_state {
                     State::DoorOpen => controller.transision_to_door_open(),
@@ -279,12 +467,272 @@ pub fn controller_loop(
                     _ => {},
                 }
 
-                elevator_event_tx.send(ElevatorEvent {
-                    direction: controller.direction,
-                    state: controller.fsm_state,
-                    floor: controller.last_floor.unwrap(),
-                }).unwrap();
+                controller.report(&elevator_event_tx);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elevator_io::mock::MockElevatorIo;
+    use std::thread::{sleep, spawn, JoinHandle};
+    use std::time::{Duration, Instant};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    // Korte tider, så testene av tidsavbrudd ikke tar evigheter
+    const DOOR_OPEN_DURATION: Duration = Duration::from_millis(300);
+    const TRAVEL_TIMEOUT: Duration = Duration::from_millis(500);
+    const OBSTRUCTION_TIMEOUT: Duration = Duration::from_millis(1000);
+    const HOMING_TIMEOUT: Duration = Duration::from_millis(300);
+
+    fn config() -> Config {
+        Config {
+            number_of_floors: 4,
+            elevator: ElevatorConfig {
+                door_open_duration: DOOR_OPEN_DURATION,
+                travel_timeout: TRAVEL_TIMEOUT,
+                obstruction_timeout: OBSTRUCTION_TIMEOUT,
+                homing_timeout: HOMING_TIMEOUT,
+                poll_period: Duration::from_millis(5),
             },
+            ..Default::default()
         }
     }
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < TIMEOUT, "Ventet for lenge");
+            sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Starter tilstandsmaskinen i en egen tråd mot en heis i minnet som står i etasje 0.
+    fn start_in_ground_floor() -> (
+        MockElevatorIo,
+        cbc::Sender<Requests>,
+        cbc::Receiver<ElevatorEvent>,
+        JoinHandle<()>,
+    ) {
+        let io = MockElevatorIo::new();
+        io.arrive_at_floor(0);
+
+        let (command_tx, command_rx) = cbc::unbounded::<Requests>();
+        let (event_tx, event_rx) = cbc::unbounded::<ElevatorEvent>();
+        let thread = spawn({
+            let io = io.clone();
+            move || controller_loop(&io, &config(), command_rx, event_tx)
+        });
+
+        // Heisen står i en etasje, så den er klar med en gang
+        let event = event_rx.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!((event.state, event.floor), (State::Idle, 0));
+        io.set_obstruction(false);
+
+        (io, command_tx, event_rx, thread)
+    }
+
+    fn cab_request(floor: usize) -> Requests {
+        let mut requests = vec![Request::default(); config().number_of_floors as usize];
+        requests[floor].cab = true;
+        requests
+    }
+
+    #[test]
+    fn serves_cab_call_against_mock_elevator() {
+        let (io, command_tx, event_rx, thread) = start_in_ground_floor();
+        command_tx.send(cab_request(2)).unwrap();
+
+        let event = event_rx.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(
+            (event.state, event.direction, event.floor),
+            (State::Moving, Direction::Up, 0)
+        );
+        assert_eq!(io.motor(), Some(Direction::Up));
+
+        io.arrive_at_floor(1);
+        let event = event_rx.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!((event.state, event.floor), (State::Moving, 1));
+
+        io.arrive_at_floor(2);
+        let event = event_rx.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!((event.state, event.floor), (State::DoorOpen, 2));
+        assert_eq!(io.motor(), Some(Direction::Stopped));
+        assert!(io.door_light_lit());
+
+        drop(command_tx);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn stop_button_stops_between_floors() {
+        let (io, command_tx, event_rx, thread) = start_in_ground_floor();
+        command_tx.send(cab_request(3)).unwrap();
+        assert_eq!(event_rx.recv_timeout(TIMEOUT).unwrap().state, State::Moving);
+        io.leave_floor();
+
+        io.set_stop_button(true);
+        assert_eq!(
+            event_rx.recv_timeout(TIMEOUT).unwrap().state,
+            State::OutOfOrder
+        );
+        assert_eq!(io.motor(), Some(Direction::Stopped));
+        assert!(io.stop_button_light_lit());
+        assert!(!io.door_light_lit());
+
+        // Fortsetter i samme retning når nødstoppen slippes
+        io.set_stop_button(false);
+        let event = event_rx.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(
+            (event.state, event.direction),
+            (State::Moving, Direction::Up)
+        );
+        assert_eq!(io.motor(), Some(Direction::Up));
+        assert!(!io.stop_button_light_lit());
+
+        drop(command_tx);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn stop_button_in_a_floor_opens_the_door() {
+        let (io, command_tx, event_rx, thread) = start_in_ground_floor();
+
+        io.set_stop_button(true);
+        let event = event_rx.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!((event.state, event.floor), (State::OutOfOrder, 0));
+        assert!(io.door_light_lit());
+        assert!(io.stop_button_light_lit());
+
+        io.set_stop_button(false);
+        assert_eq!(
+            event_rx.recv_timeout(TIMEOUT).unwrap().state,
+            State::DoorOpen
+        );
+        assert!(io.door_light_lit());
+        assert!(!io.stop_button_light_lit());
+
+        drop(command_tx);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn homes_to_a_floor_before_serving_requests() {
+        let io = MockElevatorIo::new();
+        let (command_tx, command_rx) = cbc::unbounded::<Requests>();
+        let (event_tx, event_rx) = cbc::unbounded::<ElevatorEvent>();
+        let thread = spawn({
+            let io = io.clone();
+            move || controller_loop(&io, &config(), command_rx, event_tx)
+        });
+
+        // Mellom to etasjer kjører heisen ned uten å melde fra før den vet hvor den er
+        wait_until(|| io.motor() == Some(Direction::Down));
+        io.set_obstruction(false);
+        command_tx.send(cab_request(3)).unwrap();
+        assert!(event_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // Bestillingen fra oppstarten tas med en gang heisen har funnet en etasje
+        io.arrive_at_floor(1);
+        let event = event_rx.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(
+            (event.state, event.direction, event.floor),
+            (State::Moving, Direction::Up, 1)
+        );
+        assert_eq!(io.floor_indicator_lit(), Some(1));
+        assert_eq!(io.motor(), Some(Direction::Up));
+
+        drop(command_tx);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn stalled_car_is_out_of_order_until_it_reaches_a_floor() {
+        let (io, command_tx, event_rx, thread) = start_in_ground_floor();
+        command_tx.send(cab_request(3)).unwrap();
+        assert_eq!(event_rx.recv_timeout(TIMEOUT).unwrap().state, State::Moving);
+        io.leave_floor();
+
+        let event = event_rx.recv_timeout(TRAVEL_TIMEOUT * 2).unwrap();
+        assert_eq!((event.state, event.floor), (State::OutOfOrder, 0));
+        // Motoren står fortsatt på, i tilfelle heisen kommer løs
+        assert_eq!(io.motor(), Some(Direction::Up));
+
+        io.arrive_at_floor(1);
+        let event = event_rx.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!((event.state, event.floor), (State::Moving, 1));
+
+        drop(command_tx);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn long_obstruction_is_out_of_order_until_cleared() {
+        let (io, command_tx, event_rx, thread) = start_in_ground_floor();
+        command_tx.send(cab_request(0)).unwrap();
+        assert_eq!(
+            event_rx.recv_timeout(TIMEOUT).unwrap().state,
+            State::DoorOpen
+        );
+        let obstructed_at = Instant::now();
+        io.set_obstruction(true);
+        command_tx.send(cab_request(3)).unwrap();
+
+        // Døra holdes åpen, og heisen meldes ute av drift når obstruksjonen varer
+        let event = event_rx
+            .recv_timeout(DOOR_OPEN_DURATION + OBSTRUCTION_TIMEOUT + TIMEOUT)
+            .unwrap();
+        assert_eq!((event.state, event.floor), (State::OutOfOrder, 0));
+        assert!(io.door_light_lit());
+        // Regnes fra obstruksjonen oppstod, ikke fra dørtimeren gikk ut
+        assert!(obstructed_at.elapsed() < OBSTRUCTION_TIMEOUT + DOOR_OPEN_DURATION / 2);
+        assert_eq!(io.motor(), Some(Direction::Stopped));
+
+        io.set_obstruction(false);
+        assert_eq!(
+            event_rx.recv_timeout(TIMEOUT).unwrap().state,
+            State::DoorOpen
+        );
+
+        // Døra lukkes som vanlig, og heisen tar neste bestilling
+        let event = event_rx.recv_timeout(DOOR_OPEN_DURATION + TIMEOUT).unwrap();
+        assert_eq!(
+            (event.state, event.direction),
+            (State::Moving, Direction::Up)
+        );
+        assert!(!io.door_light_lit());
+
+        drop(command_tx);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn homing_from_between_floors_ends_in_service() {
+        let io = MockElevatorIo::new();
+        let (command_tx, command_rx) = cbc::unbounded::<Requests>();
+        let (event_tx, event_rx) = cbc::unbounded::<ElevatorEvent>();
+        let thread = spawn({
+            let io = io.clone();
+            move || controller_loop(&io, &config(), command_rx, event_tx)
+        });
+        wait_until(|| io.motor() == Some(Direction::Down));
+
+        // Lenger enn både kjøretiden og homing-tiden, så heisen har snudd
+        wait_until(|| io.motor() == Some(Direction::Up));
+        sleep(TRAVEL_TIMEOUT);
+
+        io.arrive_at_floor(1);
+        let event = event_rx.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(
+            (event.state, event.direction, event.floor),
+            (State::Idle, Direction::Stopped, 1)
+        );
+        assert_eq!(io.motor(), Some(Direction::Stopped));
+        assert_eq!(io.floor_indicator_lit(), Some(1));
+
+        drop(command_tx);
+        thread.join().unwrap();
+    }
 }
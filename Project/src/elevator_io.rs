@@ -0,0 +1,57 @@
+use driver_rust::elevio;
+use std::time::Duration;
+
+use crate::elevator_controller::Direction;
+use crate::inputs::{self, RxChannels};
+
+#[cfg(test)]
+pub mod mock;
+
+/// Grensesnitt mot maskinvaren til en heis: utganger (motor og lys) og en kilde til inngangshendelser.
+pub trait ElevatorIo: Clone + Send + 'static {
+    fn motor_direction(&self, direction: Direction);
+    fn call_button_light(&self, floor: u8, call: u8, on: bool);
+    fn floor_indicator(&self, floor: u8);
+    fn door_light(&self, on: bool);
+    fn stop_button_light(&self, on: bool);
+    /// Leser etasjesensoren direkte. `None` betyr at heisen er mellom to etasjer.
+    fn floor_sensor(&self) -> Option<u8>;
+    fn obstruction(&self) -> bool;
+    /// Lager et nytt sett med kanaler som mottar alle inngangshendelser fra heisen.
+    /// Inngangene leses med `poll_period` mellomrom.
+    fn input_channels(&self, poll_period: Duration) -> RxChannels;
+}
+
+/// Heis som styres over TCP via elevio-driveren (simulator eller fysisk heis).
+impl ElevatorIo for elevio::elev::Elevator {
+    fn motor_direction(&self, direction: Direction) {
+        let direction = match direction {
+            Direction::Up => elevio::elev::DIRN_UP,
+            Direction::Down => elevio::elev::DIRN_DOWN,
+            Direction::Stopped => elevio::elev::DIRN_STOP,
+        };
+
+        elevio::elev::Elevator::motor_direction(self, direction);
+    }
+    fn call_button_light(&self, floor: u8, call: u8, on: bool) {
+        elevio::elev::Elevator::call_button_light(self, floor, call, on);
+    }
+    fn floor_indicator(&self, floor: u8) {
+        elevio::elev::Elevator::floor_indicator(self, floor);
+    }
+    fn door_light(&self, on: bool) {
+        elevio::elev::Elevator::door_light(self, on);
+    }
+    fn stop_button_light(&self, on: bool) {
+        elevio::elev::Elevator::stop_button_light(self, on);
+    }
+    fn floor_sensor(&self) -> Option<u8> {
+        elevio::elev::Elevator::floor_sensor(self)
+    }
+    fn obstruction(&self) -> bool {
+        elevio::elev::Elevator::obstruction(self)
+    }
+    fn input_channels(&self, poll_period: Duration) -> RxChannels {
+        inputs::get_input_channels(self, poll_period)
+    }
+}
@@ -0,0 +1,136 @@
+use crossbeam_channel as cbc;
+use driver_rust::elevio::poll::CallButton;
+use std::collections::HashMap;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+
+use super::ElevatorIo;
+use crate::elevator_controller::Direction;
+use crate::inputs::RxChannels;
+
+struct TxChannels {
+    // Tilstandsmaskinen leser ikke knappene, men kanalen holdes åpen som hos en ekte heis
+    _call_button_tx: cbc::Sender<CallButton>,
+    floor_sensor_tx: cbc::Sender<u8>,
+    stop_button_tx: cbc::Sender<bool>,
+    obstruction_tx: cbc::Sender<bool>,
+}
+
+#[derive(Default)]
+struct MockState {
+    motor_direction: Option<Direction>,
+    call_button_lights: HashMap<(u8, u8), bool>,
+    floor_indicator: Option<u8>,
+    door_light: bool,
+    stop_button_light: bool,
+    floor_sensor: Option<u8>,
+    obstruction: bool,
+    subscribers: Vec<TxChannels>,
+}
+
+/// Heis i minnet. Husker siste verdi på alle utganger og lar innganger trigges manuelt,
+/// slik at tilstandsmaskinen kan kjøres uten simulator.
+#[derive(Clone, Default)]
+pub struct MockElevatorIo {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockElevatorIo {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn arrive_at_floor(&self, floor: u8) {
+        let mut state = self.state.lock().unwrap();
+        state.floor_sensor = Some(floor);
+
+        for subscriber in &state.subscribers {
+            let _ = subscriber.floor_sensor_tx.send(floor);
+        }
+    }
+    pub fn leave_floor(&self) {
+        self.state.lock().unwrap().floor_sensor = None;
+    }
+    pub fn set_stop_button(&self, pressed: bool) {
+        for subscriber in &self.state.lock().unwrap().subscribers {
+            let _ = subscriber.stop_button_tx.send(pressed);
+        }
+    }
+    pub fn set_obstruction(&self, obstructed: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.obstruction = obstructed;
+
+        for subscriber in &state.subscribers {
+            let _ = subscriber.obstruction_tx.send(obstructed);
+        }
+    }
+
+    pub fn motor(&self) -> Option<Direction> {
+        self.state.lock().unwrap().motor_direction
+    }
+    pub fn call_button_lit(&self, floor: u8, call: u8) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .call_button_lights
+            .get(&(floor, call))
+            .copied()
+            .unwrap_or(false)
+    }
+    pub fn floor_indicator_lit(&self) -> Option<u8> {
+        self.state.lock().unwrap().floor_indicator
+    }
+    pub fn door_light_lit(&self) -> bool {
+        self.state.lock().unwrap().door_light
+    }
+    pub fn stop_button_light_lit(&self) -> bool {
+        self.state.lock().unwrap().stop_button_light
+    }
+}
+
+impl ElevatorIo for MockElevatorIo {
+    fn motor_direction(&self, direction: Direction) {
+        self.state.lock().unwrap().motor_direction = Some(direction);
+    }
+    fn call_button_light(&self, floor: u8, call: u8, on: bool) {
+        self.state
+            .lock()
+            .unwrap()
+            .call_button_lights
+            .insert((floor, call), on);
+    }
+    fn floor_indicator(&self, floor: u8) {
+        self.state.lock().unwrap().floor_indicator = Some(floor);
+    }
+    fn door_light(&self, on: bool) {
+        self.state.lock().unwrap().door_light = on;
+    }
+    fn stop_button_light(&self, on: bool) {
+        self.state.lock().unwrap().stop_button_light = on;
+    }
+    fn floor_sensor(&self) -> Option<u8> {
+        self.state.lock().unwrap().floor_sensor
+    }
+    fn obstruction(&self) -> bool {
+        self.state.lock().unwrap().obstruction
+    }
+    fn input_channels(&self, _poll_period: Duration) -> RxChannels {
+        let (call_button_tx, call_button_rx) = cbc::unbounded::<CallButton>();
+        let (floor_sensor_tx, floor_sensor_rx) = cbc::unbounded::<u8>();
+        let (stop_button_tx, stop_button_rx) = cbc::unbounded::<bool>();
+        let (obstruction_tx, obstruction_rx) = cbc::unbounded::<bool>();
+
+        self.state.lock().unwrap().subscribers.push(TxChannels {
+            _call_button_tx: call_button_tx,
+            floor_sensor_tx,
+            stop_button_tx,
+            obstruction_tx,
+        });
+
+        RxChannels {
+            call_button_rx,
+            floor_sensor_rx,
+            stop_button_rx,
+            obstruction_rx,
+        }
+    }
+}
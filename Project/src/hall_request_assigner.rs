@@ -1,9 +1,7 @@
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, process::Command};
+use std::{collections::HashMap, time::Duration};
 
-use crate::config::NUMBER_OF_FLOORS;
-
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Behaviour {
     #[serde(rename = "idle")]
     Idle,
@@ -13,7 +11,7 @@ pub enum Behaviour {
     DoorOpen,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Direction {
     #[serde(rename = "up")]
     Up,
@@ -23,20 +21,20 @@ pub enum Direction {
     Stop,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct State {
     pub behaviour: Behaviour,
     pub floor: u8,
     pub direction: Direction,
     #[serde(rename = "cabRequests")]
-    pub cab_requests: [bool; NUMBER_OF_FLOORS],
+    pub cab_requests: Vec<bool>,
 }
 
 pub type States = HashMap<String, State>;
 
-pub type HallRequests = [(bool, bool); NUMBER_OF_FLOORS];
+pub type HallRequests = Vec<(bool, bool)>;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HallRequestsStates {
     #[serde(rename = "hallRequests")]
     pub hall_requests: HallRequests,
@@ -45,22 +43,357 @@ pub struct HallRequestsStates {
 
 pub type HallRequestsAssignments = HashMap<String, HallRequests>;
 
+/// Fordeler bestillinger ved å simulere hver heis sin rute og gi bestillingene til heisen som
+/// blir ferdig først. Samme algoritme som `hall_request_assigner` fra TTK4145.
 pub fn run_hall_request_assigner(
     input: HallRequestsStates,
+    travel_duration: Duration,
+    door_open_duration: Duration,
 ) -> Result<HallRequestsAssignments, String> {
-    let input_json = serde_json::to_string(&input).unwrap();
+    let number_of_floors = input.hall_requests.len();
+    if number_of_floors == 0 {
+        return Err("Bygningen har ingen etasjer".to_string());
+    }
+
+    for (id, state) in &input.states {
+        if state.floor as usize >= number_of_floors {
+            return Err(format!("Heis {id} er i ugyldig etasje {}", state.floor));
+        }
+        if state.cab_requests.len() != number_of_floors {
+            return Err(format!(
+                "Heis {id} har {} etasjer, men bygningen har {number_of_floors}",
+                state.cab_requests.len()
+            ));
+        }
+    }
+
+    let mut hall_calls: Vec<[HallCall; 2]> = input
+        .hall_requests
+        .iter()
+        .map(|(up, down)| [HallCall::new(*up), HallCall::new(*down)])
+        .collect();
+
+    // Sorter på id og bruk rekkefølgen som tiebreaker, slik at resultatet er deterministisk
+    let mut ids: Vec<&String> = input.states.keys().collect();
+    ids.sort();
+    let mut simulations: Vec<Simulation> = ids
+        .into_iter()
+        .enumerate()
+        .map(|(index, id)| Simulation {
+            id: id.clone(),
+            state: input.states[id].clone(),
+            time: Duration::from_micros(index as u64),
+            number_of_floors,
+            travel_duration,
+            door_open_duration,
+        })
+        .collect();
+
+    for simulation in simulations.iter_mut() {
+        simulation.perform_initial_move(&mut hall_calls);
+    }
+
+    loop {
+        simulations.sort_by_key(|simulation| simulation.time);
+
+        let mut done = !any_unassigned(&hall_calls);
+        if unvisited_are_immediately_assignable(&hall_calls, &simulations) {
+            assign_immediate(&mut hall_calls, &mut simulations);
+            done = true;
+        }
+
+        if done {
+            break;
+        }
+
+        let Some(first) = simulations.first_mut() else {
+            break;
+        };
+        first.perform_single_move(&mut hall_calls);
+    }
+
+    let mut assignments: HallRequestsAssignments = input
+        .states
+        .keys()
+        .map(|id| (id.clone(), vec![(false, false); number_of_floors]))
+        .collect();
+
+    for (floor, [up, down]) in hall_calls.iter().enumerate() {
+        if let Some(id) = &up.assigned_to {
+            assignments.get_mut(id).unwrap()[floor].0 = true;
+        }
+        if let Some(id) = &down.assigned_to {
+            assignments.get_mut(id).unwrap()[floor].1 = true;
+        }
+    }
+
+    Ok(assignments)
+}
+
+const HALL_UP: usize = 0;
+const HALL_DOWN: usize = 1;
+
+#[derive(Debug, Clone, Default)]
+struct HallCall {
+    active: bool,
+    assigned_to: Option<String>,
+}
+
+impl HallCall {
+    fn new(active: bool) -> Self {
+        HallCall {
+            active,
+            assigned_to: None,
+        }
+    }
+    fn is_unassigned(&self) -> bool {
+        self.active && self.assigned_to.is_none()
+    }
+}
+
+type HallCalls = [[HallCall; 2]];
 
-    let output = Command::new("./hall_request_assigner")
-        .arg("--input")
-        .arg(&input_json)
-        .output()
-        .expect("Failed to start hall_request_assigner");
+fn any_unassigned(hall_calls: &HallCalls) -> bool {
+    hall_calls.iter().flatten().any(HallCall::is_unassigned)
+}
+
+/// Bestillingene en simulert heis ser: sine egne interne bestillinger og alle ufordelte etasjebestillinger.
+#[derive(Debug, Clone)]
+struct SimulatedRequests {
+    floor: usize,
+    direction: Direction,
+    hall: Vec<[bool; 2]>,
+    cab: Vec<bool>,
+}
+
+impl SimulatedRequests {
+    fn at(&self, floor: usize) -> bool {
+        self.hall[floor][HALL_UP] || self.hall[floor][HALL_DOWN] || self.cab[floor]
+    }
+    fn above(&self) -> bool {
+        (self.floor + 1..self.hall.len()).any(|floor| self.at(floor))
+    }
+    fn below(&self) -> bool {
+        (0..self.floor).any(|floor| self.at(floor))
+    }
+    fn here(&self) -> bool {
+        self.at(self.floor)
+    }
+    fn choose_direction(&self) -> Direction {
+        match self.direction {
+            Direction::Up if self.above() => Direction::Up,
+            Direction::Up if self.here() => Direction::Stop,
+            Direction::Up if self.below() => Direction::Down,
+            Direction::Up => Direction::Stop,
+            _ if self.below() => Direction::Down,
+            _ if self.here() => Direction::Stop,
+            _ if self.above() => Direction::Up,
+            _ => Direction::Stop,
+        }
+    }
+    fn should_stop(&self) -> bool {
+        let at_end = self.floor == 0 || self.floor == self.hall.len() - 1;
+
+        match self.direction {
+            Direction::Up => {
+                self.hall[self.floor][HALL_UP] || self.cab[self.floor] || !self.above() || at_end
+            }
+            Direction::Down => {
+                self.hall[self.floor][HALL_DOWN] || self.cab[self.floor] || !self.below() || at_end
+            }
+            Direction::Stop => true,
+        }
+    }
+    /// Returnerer hvilke etasjebestillinger som blir ekspedert når døren åpnes i nåværende etasje.
+    fn clear_at_current_floor(&self) -> [bool; 2] {
+        let hall = self.hall[self.floor];
+
+        match self.direction {
+            Direction::Up if hall[HALL_UP] => [true, false],
+            Direction::Up if !self.above() => [false, hall[HALL_DOWN]],
+            Direction::Down if hall[HALL_DOWN] => [false, true],
+            Direction::Down if !self.below() => [hall[HALL_UP], false],
+            Direction::Stop => hall,
+            _ => [false, false],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Simulation {
+    id: String,
+    state: State,
+    time: Duration,
+    number_of_floors: usize,
+    travel_duration: Duration,
+    door_open_duration: Duration,
+}
+
+impl Simulation {
+    fn requests(&self, hall_calls: &HallCalls) -> SimulatedRequests {
+        SimulatedRequests {
+            floor: self.state.floor as usize,
+            direction: self.state.direction,
+            hall: hall_calls
+                .iter()
+                .map(|calls| calls.each_ref().map(HallCall::is_unassigned))
+                .collect(),
+            cab: self.state.cab_requests.clone(),
+        }
+    }
+    fn step(&mut self, direction: Direction) {
+        match direction {
+            Direction::Up if (self.state.floor as usize) < self.number_of_floors - 1 => {
+                self.state.floor += 1
+            }
+            Direction::Down if self.state.floor > 0 => self.state.floor -= 1,
+            _ => {}
+        }
+    }
+    fn clear_at_current_floor(&mut self, requests: &SimulatedRequests, hall_calls: &mut HallCalls) {
+        let floor = self.state.floor as usize;
+
+        self.state.cab_requests[floor] = false;
+        for (call, cleared) in requests.clear_at_current_floor().into_iter().enumerate() {
+            if cleared {
+                hall_calls[floor][call].assigned_to = Some(self.id.clone());
+            }
+        }
+    }
+    fn perform_initial_move(&mut self, hall_calls: &mut HallCalls) {
+        match self.state.behaviour {
+            Behaviour::DoorOpen | Behaviour::Idle => {
+                if self.state.behaviour == Behaviour::DoorOpen {
+                    self.time += self.door_open_duration / 2;
+                }
+
+                // Heiser som står i samme etasje overskriver hverandre, slik at den siste vinner
+                let floor = self.state.floor as usize;
+                for call in hall_calls[floor].iter_mut() {
+                    if call.active {
+                        call.assigned_to = Some(self.id.clone());
+                        self.time += self.door_open_duration;
+                    }
+                }
+            }
+            Behaviour::Moving => {
+                self.step(self.state.direction);
+                self.time += self.travel_duration / 2;
+            }
+        }
+    }
+    fn perform_single_move(&mut self, hall_calls: &mut HallCalls) {
+        let requests = self.requests(hall_calls);
+
+        match self.state.behaviour {
+            Behaviour::Moving => {
+                if requests.should_stop() {
+                    self.state.behaviour = Behaviour::DoorOpen;
+                    self.time += self.door_open_duration;
+                    self.clear_at_current_floor(&requests, hall_calls);
+                } else {
+                    self.step(self.state.direction);
+                    self.time += self.travel_duration;
+                }
+            }
+            Behaviour::Idle | Behaviour::DoorOpen => {
+                self.state.direction = requests.choose_direction();
+
+                if self.state.direction == Direction::Stop {
+                    if requests.here() {
+                        self.clear_at_current_floor(&requests, hall_calls);
+                        self.time += self.door_open_duration;
+                        self.state.behaviour = Behaviour::DoorOpen;
+                    } else {
+                        self.state.behaviour = Behaviour::Idle;
+                    }
+                } else {
+                    self.state.behaviour = Behaviour::Moving;
+                    self.time += self.travel_duration;
+                    self.step(self.state.direction);
+                }
+            }
+        }
+    }
+}
+
+/// Sann dersom alle gjenstående bestillinger står i en etasje der en ledig heis allerede venter.
+fn unvisited_are_immediately_assignable(
+    hall_calls: &HallCalls,
+    simulations: &[Simulation],
+) -> bool {
+    if simulations
+        .iter()
+        .any(|simulation| simulation.state.cab_requests.iter().any(|cab| *cab))
+    {
+        return false;
+    }
+
+    for (floor, calls) in hall_calls.iter().enumerate() {
+        if calls.iter().all(|call| call.active) {
+            return false;
+        }
+
+        for call in calls {
+            if call.is_unassigned()
+                && !simulations
+                    .iter()
+                    .any(|simulation| simulation.state.floor as usize == floor)
+            {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+fn assign_immediate(hall_calls: &mut HallCalls, simulations: &mut [Simulation]) {
+    for (floor, calls) in hall_calls.iter_mut().enumerate() {
+        for call in calls.iter_mut() {
+            for simulation in simulations.iter_mut() {
+                if call.is_unassigned() && simulation.state.floor as usize == floor {
+                    call.assigned_to = Some(simulation.id.clone());
+                    simulation.time += simulation.door_open_duration;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tilfeldige tilstander, kjørt gjennom `hall_request_assigner`-programmet i prosjektmappa
+    // med `--travelDuration`, `--doorOpenDuration` og `--input`
+    const RECORDED_CASES: &str = include_str!("../tests/fixtures/hall_request_assigner.json");
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct RecordedCase {
+        travel_duration: u64,
+        door_open_duration: u64,
+        input: HallRequestsStates,
+        output: HallRequestsAssignments,
+    }
 
-    let assignments = serde_json::from_slice(&output.stdout).unwrap();
+    #[test]
+    fn assigns_like_the_reference_program() {
+        let cases: Vec<RecordedCase> = serde_json::from_str(RECORDED_CASES).unwrap();
+        assert!(!cases.is_empty());
 
-    if output.status.success() {
-        Ok(assignments)
-    } else {
-        Err(String::from_utf8_lossy(&output.stderr).to_string())
+        for (index, case) in cases.into_iter().enumerate() {
+            let assignments = run_hall_request_assigner(
+                case.input,
+                Duration::from_millis(case.travel_duration),
+                Duration::from_millis(case.door_open_duration),
+            )
+            .unwrap();
+            assert_eq!(
+                assignments, case.output,
+                "Ulik fordeling i tilfelle {index}"
+            );
+        }
     }
 }
@@ -10,9 +10,7 @@ pub struct RxChannels {
     pub obstruction_rx: cbc::Receiver<bool>,
 }
 
-pub fn get_input_channels(elevator: &elevio::elev::Elevator) -> RxChannels {
-    let poll_period = Duration::from_millis(25);
-
+pub fn get_input_channels(elevator: &elevio::elev::Elevator, poll_period: Duration) -> RxChannels {
     let (call_button_tx, call_button_rx) = cbc::unbounded::<elevio::poll::CallButton>();
     {
         let elevator = elevator.clone();
@@ -37,10 +35,10 @@ pub fn get_input_channels(elevator: &elevio::elev::Elevator) -> RxChannels {
         spawn(move || elevio::poll::obstruction(elevator, obstruction_tx, poll_period));
     }
 
-    return RxChannels {
+    RxChannels {
         call_button_rx,
         floor_sensor_rx,
         obstruction_rx,
         stop_button_rx,
-    };
+    }
 }
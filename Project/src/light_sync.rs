@@ -1,8 +1,9 @@
-use driver_rust::elevio::elev::{Elevator, CAB, HALL_DOWN, HALL_UP};
+use driver_rust::elevio::elev::{CAB, HALL_DOWN, HALL_UP};
 
 use crate::elevator_controller::Requests;
+use crate::elevator_io::ElevatorIo;
 
-pub fn sync_call_lights(elevator: &Elevator, requests: &Requests) {
+pub fn sync_call_lights(elevator: &impl ElevatorIo, requests: &Requests) {
     for (floor, request) in requests.iter().enumerate() {
         let floor = floor as u8;
 
@@ -11,3 +12,28 @@ pub fn sync_call_lights(elevator: &Elevator, requests: &Requests) {
         elevator.call_button_light(floor, CAB, request.cab);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elevator_controller::Request;
+    use crate::elevator_io::mock::MockElevatorIo;
+
+    #[test]
+    fn lights_follow_requests() {
+        let elevator = MockElevatorIo::new();
+        let mut requests = vec![Request::default(); 4];
+        requests[1].hall_up = true;
+        requests[3].cab = true;
+
+        sync_call_lights(&elevator, &requests);
+        assert!(elevator.call_button_lit(1, HALL_UP));
+        assert!(!elevator.call_button_lit(1, HALL_DOWN));
+        assert!(elevator.call_button_lit(3, CAB));
+
+        requests[1].hall_up = false;
+        sync_call_lights(&elevator, &requests);
+        assert!(!elevator.call_button_lit(1, HALL_UP));
+        assert!(elevator.call_button_lit(3, CAB));
+    }
+}
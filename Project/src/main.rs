@@ -1,36 +1,93 @@
+use assigner::AssignerKind;
+use backup::Role;
 use clap::Parser;
+use config::Config;
 use crossbeam_channel as cbc;
 use driver_rust::elevio;
 use elevator_controller::controller_loop;
-use env_logger;
 use log::{error, info, LevelFilter};
+use network::codec::Codec;
+use network::socket::QueuePolicy;
 use request_dispatch::{start_master_server, start_slave_client};
-use std::{process::exit, thread::spawn};
+use simulator::Simulator;
+use std::{
+    fs,
+    path::PathBuf,
+    process::exit,
+    thread::{park, spawn},
+    time::Duration,
+};
 
+mod assigner;
 mod backup;
+mod codec_benchmark;
 mod config;
 mod elevator_controller;
+mod elevator_io;
 mod hall_request_assigner;
 mod inputs;
 mod light_sync;
 mod network;
+mod node_id;
 mod request_dispatch;
+mod simulator;
+mod state_sync;
 mod system_state;
 mod timer;
+mod version_vector;
 
 #[derive(Debug, Parser)]
 struct Args {
+    /// Id-en til noden. Uten navn brukes id-en noden fikk første gang den startet.
     #[arg(long, short)]
     name: Option<String>,
 
-    #[arg(long, short, default_value_t = 15657)]
-    port: u16,
+    /// Porten til heisserveren. Overstyrer `elevator_port` i konfigurasjonsfila.
+    #[arg(long, short)]
+    port: Option<u16>,
 
     #[arg(long, short, default_value_t = false)]
     master: bool,
 
     #[arg(long, short, default_value_t = false)]
     slave: bool,
+
+    /// TOML-fil med innstillinger. Flaggene under overstyrer verdiene i fila.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Antall etasjer i bygningen. Alle noder i systemet må ha samme antall.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(2..))]
+    floors: Option<u8>,
+
+    /// Strategien masteren bruker for å fordele etasjebestillinger
+    #[arg(long, value_enum)]
+    assigner: Option<AssignerKind>,
+
+    /// Hva slaven gjør med meldinger til master mens den ikke er tilkoblet
+    #[arg(long, value_enum)]
+    queue_policy: Option<QueuePolicy>,
+
+    /// Formatet meldingene på nettverket sendes i
+    #[arg(long, value_enum)]
+    codec: Option<Codec>,
+
+    /// Start en innebygd heissimulator på porten. Sammen med --slave kjører simulatoren i bakgrunnen.
+    /// En dedikert master har ingen heis, så simulatoren kan ikke brukes sammen med --master.
+    #[arg(long, default_value_t = false, conflicts_with = "master")]
+    simulator: bool,
+
+    /// Tiden simulatoren bruker på å kjøre mellom to etasjer
+    #[arg(long, default_value_t = 2000)]
+    travel_time_ms: u64,
+
+    /// Hvor simulatorens heis starter, i etasjer fra bunnen (f.eks. 1.5 for mellom andre og tredje etasje)
+    #[arg(long, default_value_t = 0.0)]
+    start_position: f64,
+
+    /// Mål størrelsen og hastigheten til codecene på en typisk tilstand, og avslutt. Kjør med --release.
+    #[arg(long, default_value_t = false)]
+    benchmark_codecs: bool,
 }
 
 fn main() {
@@ -40,29 +97,144 @@ fn main() {
 
     let args = Args::parse();
 
-    info!("Bruker port: {}", args.port);
+    let config = match load_config(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{e}");
+            exit(1);
+        }
+    };
+
+    if args.benchmark_codecs {
+        codec_benchmark::run_codec_benchmark(config.number_of_floors);
+        return;
+    }
+
+    if let Err(e) = fs::create_dir_all(&config.backup_directory) {
+        error!("Klarte ikke lage backupmappa {}: {e}", config.backup_directory.display());
+        exit(1);
+    }
+
+    info!("Bruker port: {}", config.elevator_port);
+
+    let simulator = args.simulator.then(|| {
+        Simulator::start(
+            config.elevator_port,
+            config.number_of_floors,
+            Duration::from_millis(args.travel_time_ms),
+            args.start_position,
+        )
+        .unwrap_or_else(|e| {
+            error!("Klarte ikke starte simulatoren på port {}: {e}", config.elevator_port);
+            exit(1);
+        })
+    });
 
     if args.master {
-        start_master_server();
+        let name = resolve_node_id(args.name, &config, Role::Master);
+        start_master_server(name, &config);
         return;
     }
 
     if args.slave {
-        let elevio_driver: elevio::elev::Elevator =
-            elevio::elev::Elevator::init(&format!("localhost:{}", args.port), 4).unwrap();
+        let name = resolve_node_id(args.name, &config, Role::Slave);
+
+        let elevio_driver: elevio::elev::Elevator = elevio::elev::Elevator::init(
+            &format!("localhost:{}", config.elevator_port),
+            config.number_of_floors,
+        )
+        .unwrap();
 
         let (command_channel_tx, command_channel_rx) = cbc::unbounded();
         let (elevator_event_tx, elevator_event_rx) = cbc::unbounded();
 
         {
             let elevio_driver = elevio_driver.clone();
-            spawn(move || controller_loop(&elevio_driver, command_channel_rx, elevator_event_tx));
+            let config = config.clone();
+            spawn(move || controller_loop(&elevio_driver, &config, command_channel_rx, elevator_event_tx));
         }
 
-        start_slave_client(None, &elevio_driver, command_channel_tx, elevator_event_rx);
+        if let Some(simulator) = simulator {
+            spawn(move || simulator.run_script_from_stdin());
+        }
+
+        start_slave_client(
+            name,
+            &config,
+            &elevio_driver,
+            command_channel_tx,
+            elevator_event_rx,
+        );
         return;
     }
 
-    error!("Programmet må startes som enten master eller slave. Kjør 'cargo run master' for master eller 'cargo run slave' for slave.");
+    if let Some(simulator) = simulator {
+        simulator.run_script_from_stdin();
+        // Fortsett å simulere etter at skriptet er ferdig
+        loop {
+            park();
+        }
+    }
+
+    error!("Programmet må startes som enten master, slave eller simulator. Kjør 'cargo run -- --master' for master, 'cargo run -- --slave' for slave eller 'cargo run -- --simulator' for simulator.");
     exit(1);
 }
+
+/// Leser konfigurasjonsfila dersom den er oppgitt, og legger flaggene fra kommandolinja over.
+fn load_config(args: &Args) -> Result<Config, String> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    if let Some(port) = args.port {
+        config.elevator_port = port;
+    }
+    if let Some(floors) = args.floors {
+        config.number_of_floors = floors;
+    }
+    if let Some(assigner) = args.assigner {
+        config.assigner.kind = assigner;
+    }
+    if let Some(queue_policy) = args.queue_policy {
+        config.network.queue_policy = queue_policy;
+    }
+    if let Some(codec) = args.codec {
+        config.network.codec = codec;
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
+/// Bruker navnet fra kommandolinja, eller id-en som er lagret for rollen.
+fn resolve_node_id(name: Option<String>, config: &Config, role: Role) -> String {
+    let node_id = match name {
+        Some(name) => Ok(name),
+        None => node_id::load_or_create_node_id(&config.backup_directory, role),
+    };
+
+    match node_id {
+        Ok(node_id) if node_id::is_valid_node_id(&node_id) => node_id,
+        Ok(node_id) => {
+            error!("Ugyldig navn {node_id:?}. Bruk bare bokstaver, tall, - og _.");
+            exit(1);
+        }
+        Err(e) => {
+            error!("Klarte ikke lese eller lagre id-en til noden: {e}");
+            exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulator_is_not_allowed_with_a_dedicated_master() {
+        assert!(Args::try_parse_from(["heis", "--master", "--simulator"]).is_err());
+        assert!(Args::try_parse_from(["heis", "--slave", "--simulator"]).is_ok());
+        assert!(Args::try_parse_from(["heis", "--simulator"]).is_ok());
+    }
+}
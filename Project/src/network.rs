@@ -1,3 +1,6 @@
 pub mod advertiser;
+pub mod codec;
+pub mod election;
 pub mod elevator_monitor;
+pub mod protocol;
 pub mod socket;
@@ -1,31 +1,44 @@
+use super::codec::Codec;
 use super::socket::{Client, SendableType};
-use crate::network::elevator_monitor::ElevatorMonitor;
 use crate::timer::Timer;
 use crossbeam_channel::{select, unbounded, Receiver, Sender};
+use log::{error, warn};
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use std::{
     net::SocketAddrV4,
     thread::{spawn, JoinHandle},
-    time::Duration,
-    u8,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-const ADVERTISING_INTERVAL: Duration = Duration::from_secs(1);
-// Use port 52052 and 239.0.0.52 for group 52 <3
-const ADVERTISING_IP: [u8; 4] = [239, 0, 0, 52];
-const ADVERTISING_PORT: u16 = 52052;
-const ADVERTISER_ID_LENGTH: usize = 16;
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Advertisment<T: Clone> {
-    sender_id: [u8; ADVERTISER_ID_LENGTH],
+    sender_id: String,
+    instance: Instance,
     data: T,
 }
 
+// Tells apart two running processes that use the same sender id. The oldest one is ordered first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct Instance {
+    started_at_ms: u128,
+    nonce: u64,
+}
+
+impl Instance {
+    fn new() -> Self {
+        Instance {
+            started_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            nonce: rand::rng().next_u64(),
+        }
+    }
+}
+
 enum AdvertiserCommand<T> {
     Start,
-    Stop,
     SetAdvertisment(T),
     Exit,
 }
@@ -33,21 +46,46 @@ enum AdvertiserCommand<T> {
 pub struct Advertiser<T: SendableType + Clone> {
     control_channel_tx: Sender<AdvertiserCommand<T>>,
     receive_channel_rx: Receiver<(SocketAddrV4, T)>,
+    conflict_channel_rx: Receiver<SocketAddrV4>,
     thread: Option<JoinHandle<()>>,
 }
 
 impl<T: SendableType + Clone> Advertiser<T> {
-    pub fn init(advertisment: T) -> Self {
+    // Advertises on the given multicast group once every interval. Advertisments from other
+    // processes using the same sender id are not passed on, but reported on the conflict channel.
+    pub fn init(
+        advertisment: T,
+        sender_id: String,
+        group: SocketAddrV4,
+        interval: Duration,
+        codec: Codec,
+    ) -> Self {
         let (control_channel_tx, control_channel_rx) = unbounded::<AdvertiserCommand<T>>();
         let (receive_channel_tx, receive_channel_rx) = unbounded::<(SocketAddrV4, T)>();
+        let (conflict_channel_tx, conflict_channel_rx) = unbounded::<SocketAddrV4>();
+
+        let advertisment = Advertisment {
+            sender_id,
+            instance: Instance::new(),
+            data: advertisment,
+        };
 
         let thread = Some(spawn(move || {
-            run_advertiser(advertisment, control_channel_rx, receive_channel_tx)
+            run_advertiser(
+                advertisment,
+                group,
+                interval,
+                codec,
+                control_channel_rx,
+                receive_channel_tx,
+                conflict_channel_tx,
+            )
         }));
 
         Advertiser {
             control_channel_tx,
             receive_channel_rx,
+            conflict_channel_rx,
             thread,
         }
     }
@@ -58,12 +96,6 @@ impl<T: SendableType + Clone> Advertiser<T> {
             .unwrap();
     }
 
-    pub fn stop_advertising(&self) {
-        self.control_channel_tx
-            .send(AdvertiserCommand::Stop)
-            .unwrap();
-    }
-
     pub fn set_advertisment(&self, advertisment: T) {
         self.control_channel_tx
             .send(AdvertiserCommand::SetAdvertisment(advertisment))
@@ -73,40 +105,36 @@ impl<T: SendableType + Clone> Advertiser<T> {
     pub fn receive_channel(&self) -> &Receiver<(SocketAddrV4, T)> {
         &self.receive_channel_rx
     }
+
+    // Receives the address of an older process that advertises with the same sender id.
+    // The newest process is the one that should give way.
+    pub fn conflict_channel(&self) -> &Receiver<SocketAddrV4> {
+        &self.conflict_channel_rx
+    }
 }
 
 impl<T: SendableType + Clone> Drop for Advertiser<T> {
     fn drop(&mut self) {
-        self.control_channel_tx
-            .send(AdvertiserCommand::Exit)
-            .unwrap();
+        // The thread may already have stopped on its own
+        let _ = self.control_channel_tx.send(AdvertiserCommand::Exit);
         self.thread.take().unwrap().join().unwrap();
     }
 }
 
-fn generate_advertiser_id() -> [u8; ADVERTISER_ID_LENGTH] {
-    let mut buffer = [0; ADVERTISER_ID_LENGTH];
-    rand::rng().fill_bytes(&mut buffer);
-    return buffer;
-}
-
 fn run_advertiser<T: SendableType + Clone>(
-    advertisment_data: T,
+    mut advertisment: Advertisment<T>,
+    group: SocketAddrV4,
+    interval: Duration,
+    codec: Codec,
     control_channel_rx: Receiver<AdvertiserCommand<T>>,
     receive_channel_tx: Sender<(SocketAddrV4, T)>,
+    conflict_channel_tx: Sender<SocketAddrV4>,
 ) {
-    let mut advertisment = Advertisment {
-        sender_id: generate_advertiser_id(),
-        data: advertisment_data,
-    };
-
     let client: Client<Advertisment<T>> =
-        Client::new_multicast_client(ADVERTISING_IP, ADVERTISING_PORT);
-    let mut timer = Timer::init(ADVERTISING_INTERVAL);
+        Client::new_multicast_client(group.ip().octets(), group.port(), advertisment.sender_id.clone(), codec);
+    let mut timer = Timer::init(interval);
     let mut is_advertising = false;
 
-    let elevator_monitor = ElevatorMonitor::new();
-
     loop {
         select! {
             recv(control_channel_rx) -> command => {
@@ -119,12 +147,8 @@ fn run_advertiser<T: SendableType + Clone>(
                         is_advertising = true;
                         timer.start();
                     },
-                    AdvertiserCommand::Stop => is_advertising = false,
                     AdvertiserCommand::SetAdvertisment(new_advertisment_data) => {
-                        advertisment = Advertisment {
-                            sender_id: generate_advertiser_id(),
-                            data: new_advertisment_data,
-                        };
+                        advertisment.data = new_advertisment_data;
                     },
                     AdvertiserCommand::Exit => break,
                 }
@@ -134,19 +158,79 @@ fn run_advertiser<T: SendableType + Clone>(
                     continue;
                 }
 
-                client.sender().send(advertisment.clone()).unwrap();
+                client.send(advertisment.clone());
                 timer.start();
             },
             recv(client.receiver()) -> data => {
-                let (address, received_advertisment) = data.unwrap();
+                let Ok((address, received_advertisment)) = data else {
+                    error!("The multicast socket stopped receiving, stopping the advertiser");
+                    break;
+                };
 
                 if received_advertisment.sender_id == advertisment.sender_id {
+                    // Our own advertisment looped back
+                    if received_advertisment.instance == advertisment.instance {
+                        continue;
+                    }
+
+                    if received_advertisment.instance < advertisment.instance {
+                        conflict_channel_tx.send(address).unwrap();
+                    } else {
+                        warn!("{address} advertises with our id {}, expecting it to give way", advertisment.sender_id);
+                    }
                     continue;
                 }
 
                 receive_channel_tx.send((address, received_advertisment.data)).unwrap();
-                elevator_monitor.send_heartbeat(received_advertisment.sender_id);
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::thread::sleep;
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+    const INTERVAL: Duration = Duration::from_millis(20);
+
+    fn advertiser(data: &str, sender_id: &str, port: u16) -> Advertiser<String> {
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 0, 0, 52), port);
+        let advertiser = Advertiser::init(
+            data.to_string(),
+            sender_id.to_string(),
+            group,
+            INTERVAL,
+            Codec::Bincode,
+        );
+        advertiser.start_advertising();
+        advertiser
+    }
+
+    // Runs over the real multicast group, so it needs a network that loops multicast back
+    #[test]
+    fn advertisments_from_others_are_received() {
+        let first = advertiser("first", "advertiser-test-first", 52160);
+        let second = advertiser("second", "advertiser-test-second", 52160);
+
+        let (_, data) = first.receive_channel().recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(data, "second");
+        let (_, data) = second.receive_channel().recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(data, "first");
+    }
+
+    #[test]
+    fn newest_process_with_the_same_id_is_told_to_give_way() {
+        let oldest = advertiser("oldest", "advertiser-test-same", 52161);
+        sleep(Duration::from_millis(10));
+        let newest = advertiser("newest", "advertiser-test-same", 52161);
+
+        newest.conflict_channel().recv_timeout(TIMEOUT).unwrap();
+        assert!(oldest.conflict_channel().is_empty());
+        // Neither passes on the other's advertisment
+        assert!(oldest.receive_channel().is_empty());
+        assert!(newest.receive_channel().is_empty());
+    }
+}
@@ -0,0 +1,100 @@
+use clap::ValueEnum;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+
+/// How messages are turned into bytes on a socket. Both ends of a socket must use the same codec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Codec {
+    /// Readable in packet captures, for debugging
+    Json,
+    /// Compact and fast. Not self-describing, so serde attributes that depend on the data,
+    /// like `skip_serializing_if` or `untagged`, must not be used on messages.
+    Bincode,
+}
+
+impl Codec {
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Codec::Json => serde_json::to_vec(value).map_err(|error| error.to_string()),
+            Codec::Bincode => bincode::serialize(value).map_err(|error| error.to_string()),
+        }
+    }
+
+    /// A type made of the first fields of a message can be decoded from the whole message,
+    /// since JSON ignores unknown fields and bincode ignores trailing bytes.
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Codec::Json => serde_json::from_slice(bytes).map_err(|error| error.to_string()),
+            Codec::Bincode => bincode::deserialize(bytes).map_err(|error| error.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Codec::Json => "JSON",
+            Codec::Bincode => "bincode",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::protocol::{Envelope, EnvelopeHeader, Message, PROTOCOL_VERSION};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Data {
+        floors: Vec<bool>,
+        name: Option<String>,
+    }
+
+    fn envelopes() -> Vec<Envelope<Data>> {
+        let data = Data {
+            floors: vec![true, false, true],
+            name: Some("heis-2".to_string()),
+        };
+
+        vec![
+            Envelope::new("heis-1".to_string(), 1, Message::Hello),
+            Envelope::new("heis-1".to_string(), 2, Message::StateUpdate(data.clone())),
+            Envelope::new("heis-1".to_string(), 3, Message::Heartbeat(data)),
+            Envelope::new("heis-1".to_string(), 4, Message::Goodbye(None)),
+            Envelope::new("heis-1".to_string(), u64::MAX, Message::Goodbye(Some("Feil antall etasjer".to_string()))),
+        ]
+    }
+
+    fn assert_round_trip(codec: Codec) {
+        for envelope in envelopes() {
+            let bytes = codec.encode(&envelope).unwrap();
+            assert_eq!(codec.decode::<Envelope<Data>>(&bytes).unwrap(), envelope);
+
+            let header = codec.decode::<EnvelopeHeader>(&bytes).unwrap();
+            assert_eq!(header.protocol_version, PROTOCOL_VERSION);
+            assert_eq!(header.sender_id, "heis-1");
+        }
+    }
+
+    #[test]
+    fn envelope_round_trip_json() {
+        assert_round_trip(Codec::Json);
+    }
+
+    #[test]
+    fn envelope_round_trip_bincode() {
+        assert_round_trip(Codec::Bincode);
+    }
+
+    #[test]
+    fn codecs_do_not_read_each_other() {
+        let envelope = &envelopes()[1];
+
+        let json = Codec::Json.encode(envelope).unwrap();
+        assert!(Codec::Bincode.decode::<Envelope<Data>>(&json).is_err());
+
+        let bincode = Codec::Bincode.encode(envelope).unwrap();
+        assert!(Codec::Json.decode::<EnvelopeHeader>(&bincode).is_err());
+    }
+}
@@ -0,0 +1,417 @@
+use super::advertiser::Advertiser;
+use super::elevator_monitor::{ElevatorMonitor, PeerEvent};
+use crate::config::NetworkConfig;
+use crate::timer::Timer;
+use crossbeam_channel::{select, unbounded, Receiver, Sender};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+    thread::{spawn, JoinHandle},
+    time::Instant,
+};
+
+/// Advertised by every node. Nodes are ranked by priority first and node id second.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeAdvertisment {
+    node_id: String,
+    priority: u8,
+    number_of_floors: u8,
+    master_port: Option<u16>,
+}
+
+impl NodeAdvertisment {
+    fn rank(&self) -> (u8, &str) {
+        (self.priority, &self.node_id)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElectionEvent {
+    /// This node won the election and should start a master server
+    BecomeMaster,
+    /// A higher ranked master at the given address took over, so this node should hand its state
+    /// over to it and stop its master server
+    StepDown(SocketAddrV4),
+    /// A (new) master is reachable at the given address
+    MasterFound(SocketAddrV4),
+    /// The master stopped advertising
+    MasterLost,
+    /// An older process at the given address uses the same node id, so this node must not run
+    DuplicateNode(SocketAddrV4),
+}
+
+enum ElectionCommand {
+    SetMasterPort(Option<u16>),
+    Exit,
+}
+
+/// Bully style leader election over the multicast advertiser. When no master is alive, or the
+/// master is ranked lower than some live node, the highest ranked live node becomes master. If two
+/// masters meet, the lower ranked one steps down. Nodes with the same priority are ranked by node id.
+pub struct Election {
+    control_channel_tx: Sender<ElectionCommand>,
+    event_channel_rx: Receiver<ElectionEvent>,
+    monitor: Arc<ElevatorMonitor<String>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Election {
+    /// Only nodes with the same number of floors take part in the same election.
+    pub fn init(node_id: String, priority: u8, number_of_floors: u8, config: &NetworkConfig) -> Self {
+        let (control_channel_tx, control_channel_rx) = unbounded::<ElectionCommand>();
+        let (event_channel_tx, event_channel_rx) = unbounded::<ElectionEvent>();
+
+        let advertisment = NodeAdvertisment {
+            node_id,
+            priority,
+            number_of_floors,
+            master_port: None,
+        };
+
+        let monitor = Arc::new(ElevatorMonitor::new(
+            config.heartbeat_interval,
+            config.peer_timeout,
+        ));
+
+        let thread = {
+            let monitor = Arc::clone(&monitor);
+            let config = config.clone();
+            Some(spawn(move || {
+                run_election(
+                    advertisment,
+                    config,
+                    monitor,
+                    control_channel_rx,
+                    event_channel_tx,
+                )
+            }))
+        };
+
+        Election {
+            control_channel_tx,
+            event_channel_rx,
+            monitor,
+            thread,
+        }
+    }
+
+    /// Advertises that this node serves as master on the given port, or that it no longer does.
+    pub fn set_master_port(&self, port: Option<u16>) {
+        // Nothing is advertised once the election has stopped
+        let _ = self.control_channel_tx.send(ElectionCommand::SetMasterPort(port));
+    }
+
+    /// Closed if the election stops because the node can no longer advertise.
+    pub fn event_channel(&self) -> &Receiver<ElectionEvent> {
+        &self.event_channel_rx
+    }
+
+    /// Tells when other nodes start or stop advertising, identified by their node id.
+    pub fn subscribe_peers(&self) -> Receiver<PeerEvent<String>> {
+        self.monitor.subscribe()
+    }
+
+    /// The node ids of the nodes that are advertising right now.
+    pub fn alive_peers(&self) -> HashSet<String> {
+        self.monitor.alive_peers()
+    }
+}
+
+impl Drop for Election {
+    fn drop(&mut self) {
+        // The thread may already have stopped on its own
+        let _ = self.control_channel_tx.send(ElectionCommand::Exit);
+        self.thread.take().unwrap().join().unwrap();
+    }
+}
+
+struct KnownMaster {
+    node_id: String,
+    priority: u8,
+    address: SocketAddrV4,
+}
+
+impl KnownMaster {
+    fn rank(&self) -> (u8, &str) {
+        (self.priority, &self.node_id)
+    }
+}
+
+fn run_election(
+    mut advertisment: NodeAdvertisment,
+    config: NetworkConfig,
+    monitor: Arc<ElevatorMonitor<String>>,
+    control_channel_rx: Receiver<ElectionCommand>,
+    event_channel_tx: Sender<ElectionEvent>,
+) {
+    let group = SocketAddrV4::new(config.advertising_ip, config.advertising_port);
+    let advertiser = Advertiser::init(
+        advertisment.clone(),
+        advertisment.node_id.clone(),
+        group,
+        config.advertising_interval,
+        config.codec,
+    );
+    advertiser.start_advertising();
+
+    let mut timer = Timer::init(config.heartbeat_interval);
+    timer.start();
+
+    let peer_events = monitor.subscribe();
+
+    let started = Instant::now();
+    let mut priorities: HashMap<String, u8> = HashMap::new();
+    let mut rejected_nodes: HashSet<String> = HashSet::new();
+    let mut master: Option<KnownMaster> = None;
+    let mut is_master = false;
+
+    loop {
+        select! {
+            recv(control_channel_rx) -> command => {
+                match command.unwrap() {
+                    ElectionCommand::SetMasterPort(port) => {
+                        advertisment.master_port = port;
+                        advertiser.set_advertisment(advertisment.clone());
+
+                        if let Some(port) = port {
+                            let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+                            event_channel_tx.send(ElectionEvent::MasterFound(address)).unwrap();
+                        }
+                    },
+                    ElectionCommand::Exit => break,
+                }
+            },
+            recv(advertiser.receive_channel()) -> data => {
+                let Ok((address, received)) = data else {
+                    error!("The advertiser stopped, stopping the election");
+                    break;
+                };
+
+                if received.number_of_floors != advertisment.number_of_floors {
+                    if rejected_nodes.insert(received.node_id.clone()) {
+                        warn!(
+                            "Ignorerer {} som har {} etasjer, denne noden har {}.",
+                            received.node_id, received.number_of_floors, advertisment.number_of_floors
+                        );
+                    }
+                    continue;
+                }
+
+                priorities.insert(received.node_id.clone(), received.priority);
+                monitor.send_heartbeat(received.node_id.clone());
+
+                let Some(master_port) = received.master_port else {
+                    continue;
+                };
+
+                let master_address = SocketAddrV4::new(*address.ip(), master_port);
+
+                if is_master {
+                    if received.rank() < advertisment.rank() {
+                        continue;
+                    }
+
+                    info!("Fant en master med høyere rang ({}), trer tilbake.", received.node_id);
+                    is_master = false;
+                    event_channel_tx.send(ElectionEvent::StepDown(master_address)).unwrap();
+                }
+
+                // A lower ranked master may still be advertising until it sees the one that took over
+                if master.as_ref().is_some_and(|master| {
+                    master.node_id != received.node_id && master.rank() > received.rank()
+                }) {
+                    continue;
+                }
+
+                if master.as_ref().is_none_or(|master| master.address != master_address) {
+                    event_channel_tx.send(ElectionEvent::MasterFound(master_address)).unwrap();
+                }
+
+                master = Some(KnownMaster {
+                    node_id: received.node_id,
+                    priority: received.priority,
+                    address: master_address,
+                });
+            },
+            recv(advertiser.conflict_channel()) -> address => {
+                let Ok(address) = address else {
+                    error!("The advertiser stopped, stopping the election");
+                    break;
+                };
+                event_channel_tx.send(ElectionEvent::DuplicateNode(address)).unwrap();
+            },
+            recv(peer_events) -> event => {
+                match event.unwrap() {
+                    PeerEvent::PeerNew(node_id) => info!("Fant node {node_id}."),
+                    PeerEvent::PeerLost(node_id) => {
+                        info!("Mistet kontakt med node {node_id}.");
+                        priorities.remove(&node_id);
+
+                        if master.as_ref().is_some_and(|master| master.node_id == node_id) {
+                            info!("Mistet kontakt med master {node_id}.");
+                            master = None;
+                            event_channel_tx.send(ElectionEvent::MasterLost).unwrap();
+                        }
+                    },
+                }
+            },
+            recv(timer.timeout_channel()) -> _ => {
+                timer.start();
+
+                if is_master || started.elapsed() < config.peer_timeout {
+                    continue;
+                }
+
+                // A running master is only replaced by a node ranked higher than it
+                if master.as_ref().is_some_and(|master| master.rank() > advertisment.rank()) {
+                    continue;
+                }
+
+                let is_highest_ranked = monitor.alive_peers().iter().all(|node_id| {
+                    priorities
+                        .get(node_id)
+                        .is_none_or(|priority| (*priority, node_id.as_str()) < advertisment.rank())
+                });
+
+                if is_highest_ranked {
+                    match master.take() {
+                        Some(master) => {
+                            info!("{} har lavere rang, {} tar over som master.", master.node_id, advertisment.node_id)
+                        }
+                        None => info!("Ingen master funnet, {} blir master.", advertisment.node_id),
+                    }
+                    is_master = true;
+                    event_channel_tx.send(ElectionEvent::BecomeMaster).unwrap();
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn advertisment(node_id: &str, priority: u8) -> NodeAdvertisment {
+        NodeAdvertisment {
+            node_id: node_id.to_string(),
+            priority,
+            number_of_floors: 4,
+            master_port: None,
+        }
+    }
+
+    /// Short timings, and a port of its own so tests running at the same time never see each other.
+    fn network_config(advertising_port: u16) -> NetworkConfig {
+        NetworkConfig {
+            advertising_port,
+            advertising_interval: Duration::from_millis(50),
+            heartbeat_interval: Duration::from_millis(50),
+            peer_timeout: Duration::from_millis(300),
+            ..Default::default()
+        }
+    }
+
+    fn next_event(election: &Election) -> ElectionEvent {
+        election
+            .event_channel()
+            .recv_timeout(Duration::from_secs(3))
+            .expect("No election event")
+    }
+
+    #[test]
+    fn priority_ranks_before_node_id() {
+        assert!(advertisment("a", 1).rank() > advertisment("b", 0).rank());
+        assert!(advertisment("b", 0).rank() > advertisment("a", 0).rank());
+    }
+
+    // Runs over the real multicast group, so it needs a network that loops multicast back
+    #[test]
+    fn highest_ranked_node_becomes_master_and_is_found() {
+        let config = network_config(52152);
+        let low = Election::init("election-test-low".to_string(), 0, 4, &config);
+        let high = Election::init("election-test-high".to_string(), 1, 4, &config);
+
+        assert_eq!(next_event(&high), ElectionEvent::BecomeMaster);
+        high.set_master_port(Some(4242));
+        assert_eq!(
+            next_event(&high),
+            ElectionEvent::MasterFound(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 4242))
+        );
+
+        let ElectionEvent::MasterFound(address) = next_event(&low) else {
+            panic!("Expected the low ranked node to find the master");
+        };
+        assert_eq!(address.port(), 4242);
+        assert!(low.event_channel().is_empty());
+    }
+
+    #[test]
+    fn higher_ranked_node_takes_over_from_a_running_master() {
+        let config = network_config(52154);
+        let low = Election::init("election-test-running".to_string(), 0, 4, &config);
+        assert_eq!(next_event(&low), ElectionEvent::BecomeMaster);
+        low.set_master_port(Some(4243));
+        next_event(&low);
+
+        let high = Election::init("election-test-dedicated".to_string(), 1, 4, &config);
+        assert!(
+            matches!(next_event(&high), ElectionEvent::MasterFound(address) if address.port() == 4243)
+        );
+        assert_eq!(next_event(&high), ElectionEvent::BecomeMaster);
+        high.set_master_port(Some(4244));
+
+        let ElectionEvent::StepDown(new_master) = next_event(&low) else {
+            panic!("Expected the running master to step down");
+        };
+        assert_eq!(new_master.port(), 4244);
+        assert_eq!(next_event(&low), ElectionEvent::MasterFound(new_master));
+    }
+
+    #[test]
+    fn same_priority_is_broken_by_node_id() {
+        let config = network_config(52155);
+        let middle = Election::init("election-test-b".to_string(), 0, 4, &config);
+        assert_eq!(next_event(&middle), ElectionEvent::BecomeMaster);
+        middle.set_master_port(Some(4245));
+        next_event(&middle);
+
+        // A lower node id never takes over
+        let lower = Election::init("election-test-a".to_string(), 0, 4, &config);
+        let ElectionEvent::MasterFound(address) = next_event(&lower) else {
+            panic!("Expected the lower ranked node to find the master");
+        };
+        assert_eq!(address.port(), 4245);
+
+        // A higher node id does, and the other nodes follow it
+        let higher = Election::init("election-test-c".to_string(), 0, 4, &config);
+        assert!(
+            matches!(next_event(&higher), ElectionEvent::MasterFound(address) if address.port() == 4245)
+        );
+        assert_eq!(next_event(&higher), ElectionEvent::BecomeMaster);
+        higher.set_master_port(Some(4246));
+
+        assert!(
+            matches!(next_event(&middle), ElectionEvent::StepDown(address) if address.port() == 4246)
+        );
+        let ElectionEvent::MasterFound(address) = next_event(&lower) else {
+            panic!("Expected the lower ranked node to follow the new master");
+        };
+        assert_eq!(address.port(), 4246);
+        assert!(lower.event_channel().is_empty());
+    }
+
+    #[test]
+    fn nodes_with_another_number_of_floors_are_ignored() {
+        let config = network_config(52153);
+        let low = Election::init("election-test-four-floors".to_string(), 0, 4, &config);
+        let high = Election::init("election-test-six-floors".to_string(), 1, 6, &config);
+
+        assert_eq!(next_event(&high), ElectionEvent::BecomeMaster);
+        assert_eq!(next_event(&low), ElectionEvent::BecomeMaster);
+    }
+}
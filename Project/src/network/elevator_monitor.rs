@@ -1,89 +1,216 @@
 use crossbeam_channel::{select, unbounded, Receiver, Sender};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    sync::{Arc, Mutex},
     thread::{spawn, JoinHandle},
     time::{Duration, Instant},
 };
 
-// Check interval
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-// Time-out duration
-const ELEVATOR_TIMEOUT: Duration = Duration::from_secs(10);
-// ID-length
-const ELEVATOR_ID_LENGTH: usize = 16;
-
-// Struct for heartbeat message
-#[derive(Debug, Clone)]
-struct Heartbeat {
-    elevator_id: [u8; ELEVATOR_ID_LENGTH],
-    timestamp: Instant, // Timestamp of the heartbeat message
+// Anything that can identify a peer
+pub trait PeerId: Clone + Eq + Hash + Send + 'static {}
+
+impl<T: Clone + Eq + Hash + Send + 'static> PeerId for T {}
+
+// Events sent to subscribers when the alive set changes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent<Id> {
+    PeerNew(Id),
+    PeerLost(Id),
+}
+
+enum MonitorCommand<Id> {
+    Heartbeat(Id),
+    Subscribe(Sender<PeerEvent<Id>>),
+    Exit,
 }
 
 // Struct to monitor status
-pub struct ElevatorMonitor {
-    heartbeat_tx: Sender<Heartbeat>, // Channel to send heartbeat messages
-    thread: Option<JoinHandle<()>>,  // Handle for the monitoring thread
+pub struct ElevatorMonitor<Id: PeerId> {
+    control_channel_tx: Sender<MonitorCommand<Id>>, // Channel to send heartbeats and commands
+    alive_peers: Arc<Mutex<HashSet<Id>>>,           // Peers that have sent a heartbeat within the timeout
+    thread: Option<JoinHandle<()>>,                 // Handle for the monitoring thread
 }
 
-impl ElevatorMonitor {
-    // Creates a new ElevatorMonitor instance
-    pub fn new() -> Self {
-        // Create a channel for heartbeat messages
-        let (heartbeat_tx, heartbeat_rx) = unbounded::<Heartbeat>();
+impl<Id: PeerId> ElevatorMonitor<Id> {
+    // Creates a new ElevatorMonitor instance. Peers are checked every heartbeat interval and
+    // considered lost when they have not sent a heartbeat for the timeout duration.
+    pub fn new(heartbeat_interval: Duration, timeout: Duration) -> Self {
+        let (control_channel_tx, control_channel_rx) = unbounded::<MonitorCommand<Id>>();
+        let alive_peers = Arc::new(Mutex::new(HashSet::new()));
 
         // Spawn a thread to run the elevator monitor
-        let thread = Some(spawn(move || run_elevator_monitor(heartbeat_rx)));
+        let thread = {
+            let alive_peers = Arc::clone(&alive_peers);
+            Some(spawn(move || {
+                run_elevator_monitor(
+                    heartbeat_interval,
+                    timeout,
+                    alive_peers,
+                    control_channel_rx,
+                )
+            }))
+        };
 
         ElevatorMonitor {
-            heartbeat_tx,
+            control_channel_tx,
+            alive_peers,
             thread,
         }
     }
 
-    // Sends a heartbeat message for the given elevator ID
-    pub fn send_heartbeat(&self, elevator_id: [u8; ELEVATOR_ID_LENGTH]) {
-        self.heartbeat_tx
-            .send(Heartbeat {
-                elevator_id,
-                timestamp: Instant::now(),
-            })
+    // Sends a heartbeat message for the given peer
+    pub fn send_heartbeat(&self, id: Id) {
+        self.control_channel_tx
+            .send(MonitorCommand::Heartbeat(id))
             .unwrap();
     }
+
+    // Returns a channel that receives an event every time a peer is found or lost
+    pub fn subscribe(&self) -> Receiver<PeerEvent<Id>> {
+        let (event_channel_tx, event_channel_rx) = unbounded::<PeerEvent<Id>>();
+        self.control_channel_tx
+            .send(MonitorCommand::Subscribe(event_channel_tx))
+            .unwrap();
+        event_channel_rx
+    }
+
+    // Returns the peers that are currently alive
+    pub fn alive_peers(&self) -> HashSet<Id> {
+        self.alive_peers.lock().unwrap().clone()
+    }
 }
 
 // Implement the Drop trait to ensure the monitoring thread is properly joined when the ElevatorMonitor is dropped
-impl Drop for ElevatorMonitor {
+impl<Id: PeerId> Drop for ElevatorMonitor<Id> {
     fn drop(&mut self) {
+        self.control_channel_tx.send(MonitorCommand::Exit).unwrap();
         self.thread.take().unwrap().join().unwrap();
     }
 }
 
 // Function to run the elevator monitor
-fn run_elevator_monitor(heartbeat_rx: Receiver<Heartbeat>) {
-    // HashMap to store the last seen timestamp for each elevator
-    let mut elevator_status: HashMap<[u8; ELEVATOR_ID_LENGTH], Instant> = HashMap::new();
+fn run_elevator_monitor<Id: PeerId>(
+    heartbeat_interval: Duration,
+    timeout: Duration,
+    alive_peers: Arc<Mutex<HashSet<Id>>>,
+    control_channel_rx: Receiver<MonitorCommand<Id>>,
+) {
+    // HashMap to store the last seen timestamp for each peer
+    let mut last_seen: HashMap<Id, Instant> = HashMap::new();
+    let mut subscribers: Vec<Sender<PeerEvent<Id>>> = Vec::new();
 
     loop {
         select! {
-            // Receive a heartbeat message
-            recv(heartbeat_rx) -> heartbeat => {
-                let heartbeat = heartbeat.unwrap();
-                // Update the last seen timestamp for the elevator
-                elevator_status.insert(heartbeat.elevator_id, heartbeat.timestamp);
-            },
-            // Check for timed out elevators at regular intervals
-            default(HEARTBEAT_INTERVAL) => {
-                let now = Instant::now();
-                // Retain only the elevators that have sent a heartbeat within the timeout duration
-                elevator_status.retain(|_, &mut last_seen| now.duration_since(last_seen) < ELEVATOR_TIMEOUT);
-
-                // Iterate over the elevator statuses and print a message for any timed out elevators
-                for (elevator_id, last_seen) in &elevator_status {
-                    if now.duration_since(*last_seen) >= ELEVATOR_TIMEOUT {
-                        println!("Elevator {:?} has timed out", elevator_id);
-                    }
+            recv(control_channel_rx) -> command => {
+                match command.unwrap() {
+                    MonitorCommand::Heartbeat(id) => {
+                        // Update the last seen timestamp for the peer
+                        if last_seen.insert(id.clone(), Instant::now()).is_none() {
+                            alive_peers.lock().unwrap().insert(id.clone());
+                            publish(&mut subscribers, PeerEvent::PeerNew(id));
+                        }
+                    },
+                    MonitorCommand::Subscribe(subscriber) => subscribers.push(subscriber),
+                    MonitorCommand::Exit => break,
                 }
             },
+            // Check for timed out peers at regular intervals
+            default(heartbeat_interval) => {},
+        }
+
+        let now = Instant::now();
+        let lost_peers: Vec<Id> = last_seen
+            .iter()
+            .filter(|(_, seen)| now.duration_since(**seen) >= timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        // Remove the timed out peers before telling anyone, so that the alive set is up to date
+        for id in lost_peers {
+            last_seen.remove(&id);
+            alive_peers.lock().unwrap().remove(&id);
+            publish(&mut subscribers, PeerEvent::PeerLost(id));
+        }
+    }
+}
+
+// Sends an event to every subscriber, forgetting those that have hung up
+fn publish<Id: PeerId>(subscribers: &mut Vec<Sender<PeerEvent<Id>>>, event: PeerEvent<Id>) {
+    subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(10);
+    const PEER_TIMEOUT: Duration = Duration::from_millis(100);
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn first_heartbeat_makes_peer_alive() {
+        let monitor = ElevatorMonitor::new(HEARTBEAT_INTERVAL, PEER_TIMEOUT);
+        let events = monitor.subscribe();
+
+        monitor.send_heartbeat("heis-1");
+        assert_eq!(
+            events.recv_timeout(TIMEOUT),
+            Ok(PeerEvent::PeerNew("heis-1"))
+        );
+        assert_eq!(monitor.alive_peers(), HashSet::from(["heis-1"]));
+
+        // Later heartbeats only keep the peer alive
+        monitor.send_heartbeat("heis-1");
+        assert!(events.recv_timeout(PEER_TIMEOUT / 2).is_err());
+    }
+
+    #[test]
+    fn silent_peer_is_lost_once_after_the_timeout() {
+        let monitor = ElevatorMonitor::new(HEARTBEAT_INTERVAL, PEER_TIMEOUT);
+        let events = monitor.subscribe();
+
+        monitor.send_heartbeat("heis-1");
+        assert_eq!(
+            events.recv_timeout(TIMEOUT),
+            Ok(PeerEvent::PeerNew("heis-1"))
+        );
+        let last_heartbeat = Instant::now();
+
+        assert_eq!(
+            events.recv_timeout(TIMEOUT),
+            Ok(PeerEvent::PeerLost("heis-1"))
+        );
+        assert!(last_heartbeat.elapsed() >= PEER_TIMEOUT);
+        assert!(monitor.alive_peers().is_empty());
+        assert!(events.recv_timeout(PEER_TIMEOUT * 2).is_err());
+
+        // A lost peer that comes back is new again
+        monitor.send_heartbeat("heis-1");
+        assert_eq!(
+            events.recv_timeout(TIMEOUT),
+            Ok(PeerEvent::PeerNew("heis-1"))
+        );
+        assert_eq!(monitor.alive_peers(), HashSet::from(["heis-1"]));
+    }
+
+    #[test]
+    fn peer_kept_alive_by_heartbeats_is_not_lost() {
+        let monitor = ElevatorMonitor::new(HEARTBEAT_INTERVAL, PEER_TIMEOUT);
+        let events = monitor.subscribe();
+
+        let start = Instant::now();
+        while start.elapsed() < PEER_TIMEOUT * 3 {
+            monitor.send_heartbeat("heis-1");
+            monitor.send_heartbeat("heis-2");
+            std::thread::sleep(PEER_TIMEOUT / 5);
         }
+
+        let events: Vec<_> = events.try_iter().collect();
+        assert_eq!(events.len(), 2);
+        assert!(events
+            .iter()
+            .all(|event| matches!(event, PeerEvent::PeerNew(_))));
+        assert_eq!(monitor.alive_peers(), HashSet::from(["heis-1", "heis-2"]));
     }
 }
@@ -0,0 +1,45 @@
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever the wire format changes. Peers speaking another version are refused.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Every message on the wire, both on TCP connections and on the multicast group.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub protocol_version: u16,
+    pub sender_id: String,
+    /// Counts the messages sent by one client, starting at 1
+    pub seq: u64,
+    pub message: Message<T>,
+}
+
+/// The start of an envelope. Fields are only ever added after these, so the version of a peer
+/// can be read even when the rest of its messages can not. See `Codec::decode`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EnvelopeHeader {
+    pub protocol_version: u16,
+    pub sender_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Message<T> {
+    /// First message from both ends of a new TCP connection
+    Hello,
+    /// Data sent over a TCP connection
+    StateUpdate(T),
+    /// Data advertised on the multicast group
+    Heartbeat(T),
+    /// The sender closes the connection on purpose, with the reason if it refuses the receiver
+    Goodbye(Option<String>),
+}
+
+impl<T> Envelope<T> {
+    pub fn new(sender_id: String, seq: u64, message: Message<T>) -> Self {
+        Envelope {
+            protocol_version: PROTOCOL_VERSION,
+            sender_id,
+            seq,
+            message,
+        }
+    }
+}
@@ -1,32 +1,63 @@
-use crossbeam_channel::{select, unbounded, Receiver, Sender};
-use log::warn;
-use serde::{de, Serialize};
-use socket2::{Domain, Protocol, Socket, Type};
+use clap::ValueEnum;
+use crossbeam_channel::{after, select, unbounded, Receiver, Sender, TryRecvError};
+use log::{debug, error, info, warn};
+use serde::{de, Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
 use std::{
-    collections::HashMap,
-    io::{ErrorKind, Read, Result},
+    collections::{HashMap, HashSet, VecDeque},
+    io::{Error, ErrorKind, Read, Result, Write},
     net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4},
     thread::{sleep, spawn, JoinHandle},
     time::Duration,
 };
 
-const BUFFER_SIZE: usize = 1024;
+use super::codec::Codec;
+use super::protocol::{Envelope, EnvelopeHeader, Message, PROTOCOL_VERSION};
+
+// Largest possible UDP datagram
+const DATAGRAM_BUFFER_SIZE: usize = 65536;
+// TCP messages are framed as a 4 byte big endian length followed by the message
+const FRAME_HEADER_SIZE: usize = 4;
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
 const BACKLOG_SIZE: i32 = 128;
+// How long to wait for a TCP connection to be established
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+// Keeps a stuck peer from blocking the client when it is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+// Keepalive probes find a peer that disappeared without closing the connection, e.g. after a pulled cable
+const KEEPALIVE_TIME: Duration = Duration::from_secs(1);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+// Delay before the first reconnection attempt. It doubles for every failed attempt up to the max
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(2);
 
 pub trait SendableType: Serialize + de::DeserializeOwned + Send + 'static {}
 
 impl<T: Serialize + de::DeserializeOwned + Send + 'static> SendableType for T {}
 
+/// Sends and receives `T` wrapped in an `Envelope`. TCP clients start with a `Hello` handshake
+/// and close the connection if the peer speaks another protocol version.
 pub struct Client<T: SendableType> {
     socket: Socket,
-    sender: Option<Sender<T>>,
+    is_stream: bool,
+    sender: Option<Sender<Message<T>>>,
     receiver: Receiver<(SocketAddrV4, T)>,
     sender_thread: Option<JoinHandle<()>>,
     receiver_thread: Option<JoinHandle<()>>,
+    // Sent to the peer in the goodbye when the client is dropped
+    goodbye_reason: Option<String>,
 }
 
 impl<T: SendableType> Drop for Client<T> {
     fn drop(&mut self) {
+        // Let the peer know the connection is closed on purpose before the socket is shut down
+        let sender = self.sender.take().unwrap();
+        if self.is_stream {
+            let _ = sender.send(Message::Goodbye(self.goodbye_reason.take()));
+        }
+        drop(sender);
+        self.sender_thread.take().unwrap().join().unwrap();
+
         self.socket
             .shutdown(Shutdown::Both)
             .unwrap_or_else(|error| {
@@ -34,89 +65,172 @@ impl<T: SendableType> Drop for Client<T> {
                     panic!("Could not shutdown socket: {error:?}");
                 }
             });
-        drop(self.sender.take().unwrap());
-
-        self.sender_thread.take().unwrap().join().unwrap();
         self.receiver_thread.take().unwrap().join().unwrap();
     }
 }
 
 impl<T: SendableType> Client<T> {
-    fn new(socket: Socket, send_address: &SocketAddrV4) -> Self {
-        let mut receive_socket = socket.try_clone().unwrap();
+    fn new(socket: Socket, send_address: &SocketAddrV4, sender_id: String, codec: Codec) -> Self {
+        let receive_socket = socket.try_clone().unwrap();
         let send_socket = socket.try_clone().unwrap();
 
         let send_address = send_address.to_owned();
+        let is_stream = socket.r#type().unwrap() == Type::STREAM;
 
         let (receive_channel_tx, receive_channel_rx) = unbounded::<(SocketAddrV4, T)>();
-        let (send_channel_tx, send_channel_rx) = unbounded::<T>();
-
-        let receive_thread_handle = spawn(move || loop {
-            let mut buffer = [0; BUFFER_SIZE];
-
-            let (Ok(address), Ok(count)) = (
-                receive_socket.peek_sender(),
-                receive_socket.read(&mut buffer),
-            ) else {
-                break;
-            };
+        let (send_channel_tx, send_channel_rx) = unbounded::<Message<T>>();
+
+        if is_stream {
+            socket.set_write_timeout(Some(WRITE_TIMEOUT)).unwrap();
+            let keepalive = TcpKeepalive::new()
+                .with_time(KEEPALIVE_TIME)
+                .with_interval(KEEPALIVE_INTERVAL);
+            socket.set_tcp_keepalive(&keepalive).unwrap();
+            send_channel_tx.send(Message::Hello).unwrap();
+        }
 
-            if count == 0 {
-                break;
+        let receive_thread_handle = spawn(move || {
+            if is_stream {
+                receive_stream(receive_socket, send_address, codec, receive_channel_tx)
+            } else {
+                receive_datagrams(receive_socket, codec, receive_channel_tx)
             }
-
-            let address = address
-                .as_socket_ipv4()
-                .unwrap_or(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0));
-            let Ok(data) = serde_json::from_slice::<T>(&buffer[..count]) else {
-                warn!("Could not deserialize received data!");
-                continue;
-            };
-
-            receive_channel_tx.send((address, data.into())).unwrap();
         });
 
+        let mut seq = 0;
         let send_thread_handle = spawn(move || loop {
-            let Ok(data) = send_channel_rx.recv() else {
+            let Ok(message) = send_channel_rx.recv() else {
                 break;
             };
 
-            let Ok(buffer) = serde_json::to_vec(&data) else {
+            seq += 1;
+            let envelope = Envelope::new(sender_id.clone(), seq, message);
+            let Ok(buffer) = codec.encode(&envelope) else {
                 panic!("Could not serialize data!");
             };
 
-            send_socket.send_to(&buffer, &send_address.into()).unwrap();
+            if is_stream {
+                if let Err(error) = write_frame(&send_socket, &buffer) {
+                    warn!("Could not send data: {error}");
+                }
+            } else if let Err(error) = send_socket.send_to(&buffer, &send_address.into()) {
+                warn!("Could not send datagram to {send_address}: {error}");
+            }
         });
 
         Client {
             socket,
+            is_stream,
             sender: Some(send_channel_tx),
             receiver: receive_channel_rx,
             sender_thread: Some(send_thread_handle),
             receiver_thread: Some(receive_thread_handle),
+            goodbye_reason: None,
         }
     }
-    pub fn new_multicast_client(multicast_ip: [u8; 4], port: u16) -> Self {
+    pub fn new_multicast_client(multicast_ip: [u8; 4], port: u16, sender_id: String, codec: Codec) -> Self {
         let multicast_ip = Ipv4Addr::from(multicast_ip);
         let address = SocketAddrV4::new(multicast_ip, port);
 
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
-        socket.set_reuse_address(true).unwrap();
-        socket.bind(&address.into()).unwrap();
-        socket
-            .join_multicast_v4(&multicast_ip, &Ipv4Addr::UNSPECIFIED)
-            .unwrap();
+        // The network may not be up yet when the node starts, so keep trying until it is
+        let socket = retry_with_backoff(&format!("join multicast group {address}"), || {
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.set_reuse_address(true)?;
+            socket.bind(&address.into())?;
+            socket.join_multicast_v4(&multicast_ip, &Ipv4Addr::UNSPECIFIED)?;
+            Ok(socket)
+        });
 
-        Client::new(socket, &address)
+        Client::new(socket, &address, sender_id, codec)
     }
-    pub fn new_tcp_client(host_ip: [u8; 4], port: u16) -> Result<Self> {
+    pub fn new_tcp_client(host_ip: [u8; 4], port: u16, sender_id: String, codec: Codec) -> Result<Self> {
         let host_ip = Ipv4Addr::from(host_ip);
         let address = SocketAddrV4::new(host_ip, port);
 
         let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).unwrap();
-        socket.connect(&address.into())?;
+        socket.connect_timeout(&address.into(), CONNECT_TIMEOUT)?;
 
-        Ok(Client::new(socket, &address))
+        Ok(Client::new(socket, &address, sender_id, codec))
+    }
+    /// Sends `data` as a state update on TCP and as a heartbeat on the multicast group.
+    pub fn send(&self, data: T) {
+        let message = if self.is_stream {
+            Message::StateUpdate(data)
+        } else {
+            Message::Heartbeat(data)
+        };
+
+        self.sender.as_ref().unwrap().send(message).unwrap();
+    }
+    pub fn receiver(&self) -> &Receiver<(SocketAddrV4, T)> {
+        &self.receiver
+    }
+}
+
+/// Reported by `ReconnectingClient` and `Host` when a connection is made or lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Connected(SocketAddrV4),
+    Disconnected(SocketAddrV4),
+}
+
+/// What a reconnecting client does with messages sent while it is disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueuePolicy {
+    /// Queue the messages and send them when the connection is back, right after the first
+    /// message sent on the new connection. That lets the sender introduce itself to the host first.
+    Keep,
+    /// Throw the messages away
+    Drop,
+}
+
+/// TCP client that reconnects with exponential backoff when the connection is lost.
+/// The target is looked up again before every attempt, so it can follow a moving host.
+pub struct ReconnectingClient<T: SendableType> {
+    sender: Option<Sender<T>>,
+    reconnect_channel_tx: Sender<()>,
+    receiver: Receiver<(SocketAddrV4, T)>,
+    event_channel: Receiver<ConnectionEvent>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<T: SendableType> ReconnectingClient<T> {
+    /// `resolve_address` returns the address to connect to, or `None` if there is nothing to connect to yet.
+    pub fn new(
+        resolve_address: impl FnMut() -> Option<SocketAddrV4> + Send + 'static,
+        queue_policy: QueuePolicy,
+        sender_id: String,
+        codec: Codec,
+    ) -> Self {
+        let (send_channel_tx, send_channel_rx) = unbounded::<T>();
+        let (receive_channel_tx, receive_channel_rx) = unbounded::<(SocketAddrV4, T)>();
+        let (event_channel_tx, event_channel_rx) = unbounded::<ConnectionEvent>();
+        let (reconnect_channel_tx, reconnect_channel_rx) = unbounded::<()>();
+
+        let connect = move |address: SocketAddrV4| {
+            Client::<T>::new_tcp_client(address.ip().octets(), address.port(), sender_id.clone(), codec)
+        };
+
+        let thread = spawn(move || {
+            run_reconnecting_client(
+                resolve_address,
+                connect,
+                queue_policy,
+                reconnect_channel_rx,
+                send_channel_rx,
+                receive_channel_tx,
+                event_channel_tx,
+            )
+        });
+
+        ReconnectingClient {
+            sender: Some(send_channel_tx),
+            reconnect_channel_tx,
+            receiver: receive_channel_rx,
+            event_channel: event_channel_rx,
+            thread: Some(thread),
+        }
     }
     pub fn sender(&self) -> &Sender<T> {
         self.sender.as_ref().unwrap()
@@ -124,18 +238,305 @@ impl<T: SendableType> Client<T> {
     pub fn receiver(&self) -> &Receiver<(SocketAddrV4, T)> {
         &self.receiver
     }
+    pub fn event_channel(&self) -> &Receiver<ConnectionEvent> {
+        &self.event_channel
+    }
+    /// Drops the current connection, if any, and looks up the address again right away.
+    /// Use it when the host is known to be gone or moved, since a dead host may not close the connection.
+    pub fn reconnect(&self) {
+        self.reconnect_channel_tx.send(()).unwrap();
+    }
+}
+
+impl<T: SendableType> Drop for ReconnectingClient<T> {
+    fn drop(&mut self) {
+        // Closing the send channel stops the connection thread
+        drop(self.sender.take().unwrap());
+        self.thread.take().unwrap().join().unwrap();
+    }
+}
+
+fn run_reconnecting_client<T: SendableType>(
+    mut resolve_address: impl FnMut() -> Option<SocketAddrV4>,
+    connect: impl Fn(SocketAddrV4) -> Result<Client<T>>,
+    queue_policy: QueuePolicy,
+    reconnect_channel_rx: Receiver<()>,
+    send_channel_rx: Receiver<T>,
+    receive_channel_tx: Sender<(SocketAddrV4, T)>,
+    event_channel_tx: Sender<ConnectionEvent>,
+) {
+    let mut queue: VecDeque<T> = VecDeque::new();
+    let mut reconnect_delay = INITIAL_RECONNECT_DELAY;
+
+    loop {
+        let connection = resolve_address().and_then(|address| {
+            connect(address)
+                .inspect_err(|error| debug!("Could not connect to {address}: {error}"))
+                .ok()
+                .map(|client| (address, client))
+        });
+
+        let Some((address, client)) = connection else {
+            if !wait_for_retry(&mut reconnect_delay, queue_policy, &reconnect_channel_rx, &send_channel_rx, &mut queue) {
+                return;
+            }
+            continue;
+        };
+
+        info!("Connected to {address}");
+        event_channel_tx
+            .send(ConnectionEvent::Connected(address))
+            .unwrap();
+
+        // The delay is only reset once the host has sent something, so a host that refuses
+        // the handshake is retried with backoff
+        let mut has_received = false;
+        let mut is_reconnecting = false;
+        loop {
+            select! {
+                recv(reconnect_channel_rx) -> _ => {
+                    is_reconnecting = true;
+                    break;
+                },
+                recv(send_channel_rx) -> data => {
+                    let Ok(data) = data else { return; };
+                    client.send(data);
+
+                    for data in queue.drain(..) {
+                        client.send(data);
+                    }
+                },
+                recv(client.receiver()) -> message => {
+                    let Ok(message) = message else { break; };
+                    has_received = true;
+                    receive_channel_tx.send(message).unwrap();
+                },
+            }
+        }
+
+        drop(client);
+        if is_reconnecting {
+            info!("Closed connection to {address}, reconnecting");
+        } else {
+            warn!("Lost connection to {address}, reconnecting");
+        }
+        event_channel_tx
+            .send(ConnectionEvent::Disconnected(address))
+            .unwrap();
+
+        if has_received || is_reconnecting {
+            reconnect_delay = INITIAL_RECONNECT_DELAY;
+        } else if !wait_for_retry(&mut reconnect_delay, queue_policy, &reconnect_channel_rx, &send_channel_rx, &mut queue) {
+            return;
+        }
+    }
+}
+
+/// Waits before the next connection attempt and doubles the delay. A reconnect request ends the
+/// wait early. Returns false if the client is dropped meanwhile.
+fn wait_for_retry<T: SendableType>(
+    reconnect_delay: &mut Duration,
+    queue_policy: QueuePolicy,
+    reconnect_channel_rx: &Receiver<()>,
+    send_channel_rx: &Receiver<T>,
+    queue: &mut VecDeque<T>,
+) -> bool {
+    let retry = after(*reconnect_delay);
+    *reconnect_delay = (*reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+
+    loop {
+        select! {
+            recv(send_channel_rx) -> data => {
+                let Ok(data) = data else { return false; };
+
+                if queue_policy == QueuePolicy::Keep {
+                    queue.push_back(data);
+                }
+            },
+            recv(retry) -> _ => return true,
+            recv(reconnect_channel_rx) -> _ => {
+                *reconnect_delay = INITIAL_RECONNECT_DELAY;
+                return true;
+            },
+        }
+    }
+}
+
+/// Calls `attempt` until it succeeds, waiting with exponential backoff between the attempts.
+fn retry_with_backoff<R>(description: &str, mut attempt: impl FnMut() -> Result<R>) -> R {
+    let mut delay = INITIAL_RECONNECT_DELAY;
+
+    loop {
+        match attempt() {
+            Ok(result) => return result,
+            Err(error) => {
+                warn!("Could not {description}: {error}. Retrying in {delay:?}");
+                sleep(delay);
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+/// Receives datagrams, where every packet contains exactly one message.
+fn receive_datagrams<T: SendableType>(
+    mut socket: Socket,
+    codec: Codec,
+    receive_channel_tx: Sender<(SocketAddrV4, T)>,
+) {
+    let mut buffer = vec![0; DATAGRAM_BUFFER_SIZE];
+    // Peers speaking another protocol version or codec are only reported once
+    let mut mismatched_peers: HashSet<SocketAddrV4> = HashSet::new();
+
+    while let (Ok(address), Ok(count)) = (socket.peek_sender(), socket.read(&mut buffer)) {
+        if count == 0 {
+            break;
+        }
+
+        let address = address
+            .as_socket_ipv4()
+            .unwrap_or(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0));
+        let datagram = &buffer[..count];
+
+        match codec.decode::<EnvelopeHeader>(datagram) {
+            Ok(header) if header.protocol_version != PROTOCOL_VERSION => {
+                if mismatched_peers.insert(address) {
+                    error!(
+                        "{} at {address} speaks protocol version {}, but this node speaks {PROTOCOL_VERSION}. Ignoring it.",
+                        header.sender_id, header.protocol_version
+                    );
+                }
+                continue;
+            }
+            Ok(_) => {}
+            Err(_) => {
+                if mismatched_peers.insert(address) {
+                    error!("Could not read a {codec} message from {address}, it probably uses another codec or an older version. Ignoring it.");
+                }
+                continue;
+            }
+        }
+
+        let Ok(envelope) = codec.decode::<Envelope<T>>(datagram) else {
+            warn!("Could not deserialize received data!");
+            continue;
+        };
+        let Message::Heartbeat(data) = envelope.message else {
+            continue;
+        };
+
+        receive_channel_tx.send((address, data)).unwrap();
+    }
+}
+
+/// Receives length prefixed messages from a TCP stream. The connection is closed if the peer
+/// does not start with a hello in the same protocol version.
+fn receive_stream<T: SendableType>(
+    mut socket: Socket,
+    address: SocketAddrV4,
+    codec: Codec,
+    receive_channel_tx: Sender<(SocketAddrV4, T)>,
+) {
+    let Some(peer_id) = receive_hello::<T>(&mut socket, address, codec) else {
+        let _ = socket.shutdown(Shutdown::Both);
+        return;
+    };
+    debug!("Handshake with {peer_id} at {address} done");
+
+    while let Ok(buffer) = read_frame(&mut socket) {
+        let Ok(envelope) = codec.decode::<Envelope<T>>(&buffer) else {
+            warn!("Could not deserialize received data!");
+            continue;
+        };
+
+        match envelope.message {
+            Message::StateUpdate(data) => {
+                if receive_channel_tx.send((address, data)).is_err() {
+                    break;
+                }
+            }
+            Message::Goodbye(None) => {
+                info!("{peer_id} at {address} closed the connection");
+                break;
+            }
+            Message::Goodbye(Some(reason)) => {
+                warn!("{peer_id} at {address} closed the connection: {reason}");
+                break;
+            }
+            Message::Hello | Message::Heartbeat(_) => {
+                debug!("Ignoring unexpected message {} from {peer_id}", envelope.seq);
+            }
+        }
+    }
+}
+
+/// Reads the first message on a connection and returns the id of the peer if it is a hello in our protocol version.
+fn receive_hello<T: SendableType>(reader: &mut impl Read, address: SocketAddrV4, codec: Codec) -> Option<String> {
+    let buffer = read_frame(reader).ok()?;
+
+    let Ok(header) = codec.decode::<EnvelopeHeader>(&buffer) else {
+        error!("{address} did not start with a {codec} hello, it probably runs an older version or another codec. Closing the connection.");
+        return None;
+    };
+
+    if header.protocol_version != PROTOCOL_VERSION {
+        error!(
+            "{} at {address} speaks protocol version {}, but this node speaks {PROTOCOL_VERSION}. Closing the connection.",
+            header.sender_id, header.protocol_version
+        );
+        return None;
+    }
+
+    match codec.decode::<Envelope<T>>(&buffer) {
+        Ok(Envelope { message: Message::Hello, .. }) => Some(header.sender_id),
+        _ => {
+            error!("{} at {address} did not start with a hello. Closing the connection.", header.sender_id);
+            None
+        }
+    }
+}
+
+fn read_frame(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let mut header = [0; FRAME_HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+
+    let length = u32::from_be_bytes(header) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Frame of {length} bytes exceeds maximum frame size"),
+        ));
+    }
+
+    let mut buffer = vec![0; length];
+    reader.read_exact(&mut buffer)?;
+
+    Ok(buffer)
+}
+
+fn write_frame(mut writer: impl Write, buffer: &[u8]) -> Result<()> {
+    let length = u32::try_from(buffer.len())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "Message too large to send"))?;
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + buffer.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(buffer);
+
+    writer.write_all(&frame)
 }
 
 pub struct Host<T: SendableType> {
     socket: Socket,
     send_channel: Option<Sender<(SocketAddrV4, T)>>,
+    disconnect_channel: Sender<(SocketAddrV4, String)>,
     receive_channel: Receiver<(SocketAddrV4, T)>,
+    event_channel: Receiver<ConnectionEvent>,
     accept_thread_handle: Option<JoinHandle<()>>,
     serve_thread_handle: Option<JoinHandle<()>>,
 }
 
 impl<T: SendableType> Host<T> {
-    pub fn new_tcp_host(port: Option<u16>) -> Self {
+    pub fn new_tcp_host(port: Option<u16>, sender_id: String, codec: Codec) -> Self {
         let address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port.unwrap_or(0)));
 
         let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).unwrap();
@@ -147,6 +548,8 @@ impl<T: SendableType> Host<T> {
             unbounded::<(SocketAddrV4, Client<T>)>();
         let (receive_channel_tx, receive_channel_rx) = unbounded::<(SocketAddrV4, T)>();
         let (send_channel_tx, send_channel_rx) = unbounded::<(SocketAddrV4, T)>();
+        let (disconnect_channel_tx, disconnect_channel_rx) = unbounded::<(SocketAddrV4, String)>();
+        let (event_channel_tx, event_channel_rx) = unbounded::<ConnectionEvent>();
 
         let accept_socket: Socket = socket.try_clone().unwrap();
         let accept_thread_handle = spawn(move || loop {
@@ -155,7 +558,7 @@ impl<T: SendableType> Host<T> {
             };
 
             let client_address = client_address.as_socket_ipv4().unwrap();
-            let clients = Client::new(client_socket, &client_address);
+            let clients = Client::new(client_socket, &client_address, sender_id.clone(), codec);
 
             new_client_channel_tx
                 .send((client_address, clients))
@@ -171,6 +574,7 @@ impl<T: SendableType> Host<T> {
                         let Ok((address, client)) = new_client else { break; };
 
                         clients.insert(address, client);
+                        event_channel_tx.send(ConnectionEvent::Connected(address)).unwrap();
                     },
                     recv(send_channel_rx) -> message => {
                         let Ok((address, data)) = message else { break; };
@@ -178,12 +582,34 @@ impl<T: SendableType> Host<T> {
                             warn!("Warning: Tried sending to an unconnected address");
                             continue;
                         };
-                        client.sender().send(data).unwrap();
+                        client.send(data);
+                    }
+                    recv(disconnect_channel_rx) -> message => {
+                        let Ok((address, reason)) = message else { break; };
+                        let Some(mut client) = clients.remove(&address) else {
+                            continue;
+                        };
+
+                        // Dropping the client sends the goodbye and closes the connection
+                        client.goodbye_reason = Some(reason);
+                        drop(client);
+                        event_channel_tx.send(ConnectionEvent::Disconnected(address)).unwrap();
                     }
                     default => {
+                        let mut disconnected_addresses = Vec::new();
+
                         for (address, client) in &clients {
-                            let Ok((_, data)) = client.receiver().try_recv() else { continue; };
-                            receive_channel_tx.send((*address, data)).unwrap();
+                            match client.receiver().try_recv() {
+                                Ok((_, data)) => receive_channel_tx.send((*address, data)).unwrap(),
+                                // The receiver thread stops when the connection is closed
+                                Err(TryRecvError::Disconnected) => disconnected_addresses.push(*address),
+                                Err(TryRecvError::Empty) => {},
+                            }
+                        }
+
+                        for address in disconnected_addresses {
+                            clients.remove(&address);
+                            event_channel_tx.send(ConnectionEvent::Disconnected(address)).unwrap();
                         }
                         sleep(Duration::from_millis(10));
                     }
@@ -194,7 +620,9 @@ impl<T: SendableType> Host<T> {
         Host {
             socket,
             send_channel: Some(send_channel_tx),
+            disconnect_channel: disconnect_channel_tx,
             receive_channel: receive_channel_rx,
+            event_channel: event_channel_rx,
             accept_thread_handle: Some(accept_thread_handle),
             serve_thread_handle: Some(serve_thread_handle),
         }
@@ -205,6 +633,14 @@ impl<T: SendableType> Host<T> {
     pub fn receive_channel(&self) -> &Receiver<(SocketAddrV4, T)> {
         &self.receive_channel
     }
+    /// Closes the connection to a client, telling it why in the goodbye.
+    pub fn disconnect(&self, address: SocketAddrV4, reason: String) {
+        self.disconnect_channel.send((address, reason)).unwrap();
+    }
+    /// Tells when clients connect and disconnect.
+    pub fn event_channel(&self) -> &Receiver<ConnectionEvent> {
+        &self.event_channel
+    }
     pub fn port(&self) -> u16 {
         self.socket
             .local_addr()
@@ -224,3 +660,362 @@ impl<T: SendableType> Drop for Host<T> {
         self.serve_thread_handle.take().unwrap().join().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    // Hands out at most one byte per read, like a slow connection
+    struct Trickle<R: Read>(R);
+
+    impl<R: Read> Read for Trickle<R> {
+        fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+            let end = buffer.len().min(1);
+            self.0.read(&mut buffer[..end])
+        }
+    }
+
+    fn frames(messages: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for message in messages {
+            write_frame(&mut bytes, message).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn frame_is_length_prefixed() {
+        assert_eq!(frames(&[b"hei"]), [0, 0, 0, 3, b'h', b'e', b'i']);
+        assert_eq!(frames(&[b""]), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn frames_read_back_to_back() {
+        let mut reader = Cursor::new(frames(&[b"first", b"", b"third"]));
+
+        assert_eq!(read_frame(&mut reader).unwrap(), b"first");
+        assert_eq!(read_frame(&mut reader).unwrap(), b"");
+        assert_eq!(read_frame(&mut reader).unwrap(), b"third");
+        assert_eq!(
+            read_frame(&mut reader).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn frames_read_from_partial_reads() {
+        let mut reader = Trickle(Cursor::new(frames(&[b"first", b"second"])));
+
+        assert_eq!(read_frame(&mut reader).unwrap(), b"first");
+        assert_eq!(read_frame(&mut reader).unwrap(), b"second");
+    }
+
+    #[test]
+    fn truncated_frame_is_an_error() {
+        let mut bytes = frames(&[b"complete"]);
+        bytes.truncate(bytes.len() - 1);
+
+        let error = read_frame(&mut Cursor::new(bytes)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn oversized_length_prefix_is_rejected() {
+        let mut bytes = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(b"rest");
+
+        let error = read_frame(&mut Cursor::new(bytes)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn failed_attempts_are_retried_until_one_succeeds() {
+        let mut attempts = 0;
+        let result = retry_with_backoff("count", || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(Error::new(ErrorKind::AddrNotAvailable, "no network"))
+            } else {
+                Ok(attempts)
+            }
+        });
+
+        assert_eq!(result, 3);
+    }
+
+    fn first_frame(envelope: &Envelope<String>, codec: Codec) -> Cursor<Vec<u8>> {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, &codec.encode(envelope).unwrap()).unwrap();
+        Cursor::new(bytes)
+    }
+
+    #[test]
+    fn hello_in_our_version_is_accepted() {
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1);
+
+        for codec in [Codec::Json, Codec::Bincode] {
+            let hello = Envelope::new("peer".to_string(), 1, Message::Hello);
+            let peer_id = receive_hello::<String>(&mut first_frame(&hello, codec), address, codec);
+            assert_eq!(peer_id.as_deref(), Some("peer"));
+        }
+    }
+
+    #[test]
+    fn hello_in_another_version_is_refused() {
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1);
+
+        for codec in [Codec::Json, Codec::Bincode] {
+            let mut hello = Envelope::new("peer".to_string(), 1, Message::<String>::Hello);
+            hello.protocol_version = PROTOCOL_VERSION + 1;
+            assert_eq!(
+                receive_hello::<String>(&mut first_frame(&hello, codec), address, codec),
+                None
+            );
+
+            // A peer that skips the hello is refused too
+            let update = Envelope::new(
+                "peer".to_string(),
+                1,
+                Message::StateUpdate("hei".to_string()),
+            );
+            assert_eq!(
+                receive_hello::<String>(&mut first_frame(&update, codec), address, codec),
+                None
+            );
+        }
+
+        // A peer using the other codec
+        let hello = Envelope::new("peer".to_string(), 1, Message::<String>::Hello);
+        assert_eq!(
+            receive_hello::<String>(
+                &mut first_frame(&hello, Codec::Bincode),
+                address,
+                Codec::Json
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn disconnect_says_goodbye_with_the_reason() {
+        let host: Host<String> = Host::new_tcp_host(None, "host".to_string(), Codec::Json);
+        let mut peer = std::net::TcpStream::connect(local_address(&host)).unwrap();
+        peer.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let hello = Envelope::new("peer".to_string(), 1, Message::<String>::Hello);
+        write_frame(&mut peer, &Codec::Json.encode(&hello).unwrap()).unwrap();
+
+        let ConnectionEvent::Connected(address) =
+            host.event_channel().recv_timeout(TIMEOUT).unwrap()
+        else {
+            panic!("Expected the peer to connect");
+        };
+        host.disconnect(address, "Wrong number of floors".to_string());
+        assert_eq!(
+            host.event_channel().recv_timeout(TIMEOUT).unwrap(),
+            ConnectionEvent::Disconnected(address)
+        );
+
+        let mut receive = || {
+            Codec::Json
+                .decode::<Envelope<String>>(&read_frame(&mut peer).unwrap())
+                .unwrap()
+                .message
+        };
+        assert_eq!(receive(), Message::Hello);
+        assert_eq!(
+            receive(),
+            Message::Goodbye(Some("Wrong number of floors".to_string()))
+        );
+        assert_eq!(
+            read_frame(&mut peer).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn frames_cross_a_tcp_connection_in_pieces() {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut writer = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut reader, _) = listener.accept().unwrap();
+
+        // Two frames split at odd places, so neither arrives whole in one segment
+        let bytes = frames(&[b"first", b"second"]);
+        let reader_thread = spawn(move || {
+            [
+                read_frame(&mut reader).unwrap(),
+                read_frame(&mut reader).unwrap(),
+            ]
+        });
+        for piece in bytes.chunks(3) {
+            writer.write_all(piece).unwrap();
+            writer.flush().unwrap();
+            sleep(Duration::from_millis(5));
+        }
+
+        let [first, second] = reader_thread.join().unwrap();
+        assert_eq!(first, b"first");
+        assert_eq!(second, b"second");
+    }
+
+    #[test]
+    fn messages_cross_between_host_and_client() {
+        let host: Host<String> = Host::new_tcp_host(None, "host".to_string(), Codec::Bincode);
+        let client: Client<String> = Client::new_tcp_client(
+            [127, 0, 0, 1],
+            host.port(),
+            "client".to_string(),
+            Codec::Bincode,
+        )
+        .unwrap();
+
+        client.send("first".to_string());
+        client.send("second".to_string());
+        let (address, first) = host.receive_channel().recv_timeout(TIMEOUT).unwrap();
+        let (_, second) = host.receive_channel().recv_timeout(TIMEOUT).unwrap();
+        assert_eq!((first.as_str(), second.as_str()), ("first", "second"));
+
+        host.send_channel()
+            .send((address, "reply".to_string()))
+            .unwrap();
+        let (_, reply) = client.receiver().recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(reply, "reply");
+    }
+
+    fn local_address(host: &Host<String>) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, host.port())
+    }
+
+    /// Client that connects to whatever address is in the returned slot. It is returned after it
+    /// has found the slot empty once, so it starts out disconnected.
+    fn reconnecting_client(
+        queue_policy: QueuePolicy,
+    ) -> (ReconnectingClient<String>, Arc<Mutex<Option<SocketAddrV4>>>) {
+        let address = Arc::new(Mutex::new(None));
+        let (resolved_tx, resolved_rx) = unbounded::<()>();
+        let client = {
+            let address = Arc::clone(&address);
+            ReconnectingClient::new(
+                move || {
+                    let _ = resolved_tx.send(());
+                    *address.lock().unwrap()
+                },
+                queue_policy,
+                "client".to_string(),
+                Codec::Bincode,
+            )
+        };
+
+        resolved_rx.recv_timeout(TIMEOUT).unwrap();
+        (client, address)
+    }
+
+    #[test]
+    fn kept_messages_follow_the_first_message_on_the_new_connection() {
+        let (client, address) = reconnecting_client(QueuePolicy::Keep);
+        client.sender().send("first".to_string()).unwrap();
+        client.sender().send("second".to_string()).unwrap();
+
+        let host: Host<String> = Host::new_tcp_host(None, "host".to_string(), Codec::Bincode);
+        *address.lock().unwrap() = Some(local_address(&host));
+
+        let event = client.event_channel().recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(event, ConnectionEvent::Connected(local_address(&host)));
+        client.sender().send("hello".to_string()).unwrap();
+
+        let received: Vec<String> = (0..3)
+            .map(|_| host.receive_channel().recv_timeout(TIMEOUT).unwrap().1)
+            .collect();
+        assert_eq!(received, ["hello", "first", "second"]);
+    }
+
+    #[test]
+    fn dropped_messages_are_not_sent() {
+        let (client, address) = reconnecting_client(QueuePolicy::Drop);
+        client.sender().send("lost".to_string()).unwrap();
+
+        let host: Host<String> = Host::new_tcp_host(None, "host".to_string(), Codec::Bincode);
+        *address.lock().unwrap() = Some(local_address(&host));
+        client.event_channel().recv_timeout(TIMEOUT).unwrap();
+
+        client.sender().send("sent".to_string()).unwrap();
+        let (_, message) = host.receive_channel().recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(message, "sent");
+    }
+
+    #[test]
+    fn client_reconnects_to_a_new_host() {
+        let (client, address) = reconnecting_client(QueuePolicy::Drop);
+        let old_host: Host<String> = Host::new_tcp_host(None, "host".to_string(), Codec::Bincode);
+        let old_address = local_address(&old_host);
+        *address.lock().unwrap() = Some(old_address);
+
+        let event = client.event_channel().recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(event, ConnectionEvent::Connected(old_address));
+
+        let new_host: Host<String> = Host::new_tcp_host(None, "host".to_string(), Codec::Bincode);
+        *address.lock().unwrap() = Some(local_address(&new_host));
+        drop(old_host);
+
+        let event = client.event_channel().recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(event, ConnectionEvent::Disconnected(old_address));
+        let event = client.event_channel().recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(event, ConnectionEvent::Connected(local_address(&new_host)));
+
+        client.sender().send("hei".to_string()).unwrap();
+        let (_, message) = new_host.receive_channel().recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(message, "hei");
+    }
+
+    #[test]
+    fn reconnect_moves_the_client_to_the_new_address() {
+        let (client, address) = reconnecting_client(QueuePolicy::Drop);
+        let old_host: Host<String> = Host::new_tcp_host(None, "old".to_string(), Codec::Bincode);
+        let new_host: Host<String> = Host::new_tcp_host(None, "new".to_string(), Codec::Bincode);
+        *address.lock().unwrap() = Some(local_address(&old_host));
+
+        let event = client.event_channel().recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(event, ConnectionEvent::Connected(local_address(&old_host)));
+
+        // The old host is still up, so only the reconnect makes the client leave it
+        *address.lock().unwrap() = Some(local_address(&new_host));
+        client.reconnect();
+
+        let event = client.event_channel().recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(
+            event,
+            ConnectionEvent::Disconnected(local_address(&old_host))
+        );
+        let event = client.event_channel().recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(event, ConnectionEvent::Connected(local_address(&new_host)));
+
+        client.sender().send("hei".to_string()).unwrap();
+        let (_, message) = new_host.receive_channel().recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(message, "hei");
+    }
+
+    #[test]
+    fn host_reports_clients_connecting_and_disconnecting() {
+        let host: Host<String> = Host::new_tcp_host(None, "host".to_string(), Codec::Bincode);
+        let client: Client<String> = Client::new_tcp_client(
+            [127, 0, 0, 1],
+            host.port(),
+            "client".to_string(),
+            Codec::Bincode,
+        )
+        .unwrap();
+
+        let ConnectionEvent::Connected(address) =
+            host.event_channel().recv_timeout(TIMEOUT).unwrap()
+        else {
+            panic!("Expected the client to connect");
+        };
+
+        drop(client);
+        let event = host.event_channel().recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(event, ConnectionEvent::Disconnected(address));
+    }
+}
@@ -0,0 +1,107 @@
+use log::{info, warn};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::backup::Role;
+
+/// Henter id-en noden har brukt før, slik at den kjennes igjen av de andre etter en omstart.
+/// Første gang noden starter lages et tilfeldig dyrenavn som lagres i `directory`.
+pub fn load_or_create_node_id(directory: &Path, role: Role) -> io::Result<String> {
+    let path = directory.join(match role {
+        Role::Master => "master.id",
+        Role::Slave => "slave.id",
+    });
+
+    match fs::read_to_string(&path) {
+        Ok(node_id) if is_valid_node_id(node_id.trim()) => return Ok(node_id.trim().to_string()),
+        Ok(_) => warn!("{} inneholder ikke en gyldig id, lager en ny.", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    // Bruk et tilfeldig dyr som id :)
+    let node_id = petname::petname(1, "").unwrap();
+
+    let temporary_path = path.with_extension("id.tmp");
+    fs::write(&temporary_path, &node_id)?;
+    fs::rename(&temporary_path, &path)?;
+
+    info!("Lagret ny id {node_id} i {}.", path.display());
+    Ok(node_id)
+}
+
+/// Id-en brukes i filnavn, så den kan ikke inneholde skilletegn.
+pub fn is_valid_node_id(node_id: &str) -> bool {
+    !node_id.is_empty()
+        && node_id
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // Egen mappe for hver test, så testene kan kjøre samtidig
+    fn test_directory(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("vertikale-magier-id-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn same_id_is_loaded_after_a_restart() {
+        let directory = test_directory("omstart");
+
+        let node_id = load_or_create_node_id(&directory, Role::Slave).unwrap();
+        assert!(is_valid_node_id(&node_id));
+        assert_eq!(
+            load_or_create_node_id(&directory, Role::Slave).unwrap(),
+            node_id
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn master_and_slave_have_separate_files() {
+        let directory = test_directory("roller");
+        fs::write(directory.join("slave.id"), "heis-1\n").unwrap();
+
+        assert_eq!(
+            load_or_create_node_id(&directory, Role::Slave).unwrap(),
+            "heis-1"
+        );
+        let master_id = load_or_create_node_id(&directory, Role::Master).unwrap();
+        assert_ne!(master_id, "heis-1");
+        assert_eq!(
+            fs::read_to_string(directory.join("master.id")).unwrap(),
+            master_id
+        );
+        assert_eq!(
+            load_or_create_node_id(&directory, Role::Slave).unwrap(),
+            "heis-1"
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn invalid_id_is_replaced() {
+        let directory = test_directory("ugyldig");
+        fs::write(directory.join("slave.id"), "../heis").unwrap();
+
+        let node_id = load_or_create_node_id(&directory, Role::Slave).unwrap();
+        assert!(is_valid_node_id(&node_id));
+        assert_eq!(
+            fs::read_to_string(directory.join("slave.id")).unwrap(),
+            node_id
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,164 +1,607 @@
 use crossbeam_channel as cbc;
 use crossbeam_channel::select;
-use driver_rust::elevio;
 use driver_rust::elevio::elev::{CAB, HALL_DOWN, HALL_UP};
-use log::{debug, error, info};
-use std::collections::HashSet;
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddrV4;
-
-use crate::backup::{load_state_from_file, save_state_to_file};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, JoinHandle};
+
+use crate::assigner::{create_assigner, Assigner};
+use crate::backup::{backup_path, load_state_from_file, BackupError, BackupWriter, Role};
+use crate::config::Config;
 use crate::elevator_controller::{Direction, ElevatorEvent, Requests, State};
-use crate::inputs;
+use crate::elevator_io::ElevatorIo;
 use crate::light_sync::sync_call_lights;
-use crate::network::advertiser::Advertiser;
-use crate::network::socket::{Client, Host};
+use crate::network::election::{Election, ElectionEvent};
+use crate::network::elevator_monitor::PeerEvent;
+use crate::network::socket::{Client, ConnectionEvent, Host, ReconnectingClient};
+use crate::state_sync::{self, Delta, StateChange, StateUpdate};
 use crate::system_state::{ElevatorState, HallRequestState, SystemState};
+use crate::version_vector::Causality;
+
+// Den dedikerte masteren foretrekkes framfor slavene når det velges ny master
+const MASTER_NODE_PRIORITY: u8 = 1;
+const SLAVE_NODE_PRIORITY: u8 = 0;
+
+/// Masterserver som kjører i en egen tråd til den droppes.
+pub struct MasterServer {
+    // Adressen til masteren som tar over, dersom tilstanden skal gis videre før serveren stopper
+    exit_channel_tx: cbc::Sender<Option<SocketAddrV4>>,
+    new_master: Option<SocketAddrV4>,
+    port: u16,
+    thread: Option<JoinHandle<()>>,
+}
 
-/// Starter TCP-server for Master og fordeler innkommende bestillinger
-pub fn start_master_server() {
-    // Load state from backup if available
-    let mut master_system_state = match load_state_from_file("backup.json") {
-        Ok(states) => {
-            info!("Loaded backup.");
-            states
-        }
-        Err(_) => {
-            info!("No backup found.");
-            Default::default()
+impl MasterServer {
+    /// Starter TCP-server for Master med `initial_state` som utgangspunkt og fordeler innkommende bestillinger.
+    /// Bestillingene til heiser som `peer_events` melder tapt fordeles på nytt. Heiser i `initial_state` som ikke er
+    /// blant `alive_peers` får ikke bestillinger før de har koblet til, og fjernes om de ikke gjør det innen
+    /// `peer_timeout`. Tilstanden lagres i `backup_path`.
+    pub fn start(
+        initial_state: SystemState,
+        config: Config,
+        backup_path: PathBuf,
+        peer_events: cbc::Receiver<PeerEvent<String>>,
+        alive_peers: HashSet<String>,
+    ) -> Self {
+        let (exit_channel_tx, exit_channel_rx) = cbc::unbounded::<Option<SocketAddrV4>>();
+        let (port_channel_tx, port_channel_rx) = cbc::bounded::<u16>(1);
+
+        let thread = Some(spawn(move || {
+            run_master_server(
+                initial_state,
+                config,
+                backup_path,
+                peer_events,
+                alive_peers,
+                port_channel_tx,
+                exit_channel_rx,
+            )
+        }));
+
+        MasterServer {
+            exit_channel_tx,
+            new_master: None,
+            port: port_channel_rx.recv().unwrap(),
+            thread,
         }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Stopper serveren etter å ha gitt tilstanden videre til masteren på `new_master`.
+    pub fn hand_over(mut self, new_master: SocketAddrV4) {
+        self.new_master = Some(new_master);
+    }
+}
+
+impl Drop for MasterServer {
+    fn drop(&mut self) {
+        self.exit_channel_tx.send(self.new_master.take()).unwrap();
+        self.thread.take().unwrap().join().unwrap();
+    }
+}
+
+fn run_master_server(
+    mut master_system_state: SystemState,
+    config: Config,
+    backup_path: PathBuf,
+    peer_events: cbc::Receiver<PeerEvent<String>>,
+    alive_peers: HashSet<String>,
+    port_channel_tx: cbc::Sender<u16>,
+    exit_channel_rx: cbc::Receiver<Option<SocketAddrV4>>,
+) {
+    let mut assigner = create_assigner(&config);
+    info!("Fordeler bestillinger med {}.", assigner.name());
+    let mut backup = BackupWriter::new(backup_path);
+
+    // Heisene i backupen eller fra forrige master kan ha forsvunnet mens ingen var master. De får ikke
+    // bestillinger før de har sendt tilstanden sin, og fjernes dersom de ikke gjør det innen tidsfristen
+    let mut pending_elevators = mark_unseen_elevators(&mut master_system_state, &alive_peers);
+    let mut pending_deadline = if pending_elevators.is_empty() {
+        cbc::never()
+    } else {
+        cbc::after(config.network.peer_timeout)
     };
+    master_system_state.reassign_requests(assigner.as_mut());
 
-    let host: Host<SystemState> = Host::new_tcp_host(None);
+    let host: Host<StateUpdate> = Host::new_tcp_host(None, master_system_state.name.clone(), config.network.codec);
     info!("Master lytter på port: {}", host.port());
+    port_channel_tx.send(host.port()).unwrap();
 
-    // Start å informere slaver om at master eksisterer
-    let advertiser = Advertiser::init(host.port());
-    advertiser.start_advertising();
+    // Endringer gjort som master telles for seg, slik at de ikke blandes med endringene
+    // noden gjør som slave når en slave har blitt master
+    let version_id = format!("{}:master", master_system_state.name);
 
-    let mut slave_addresses: HashSet<SocketAddrV4> = HashSet::new();
+    // Alle tilkoblinger får endringene, også før slaven har sendt tilstanden sin
+    let mut connections: HashSet<SocketAddrV4> = HashSet::new();
+    // Navnet på heisen til hver slave som har sendt tilstanden sin
+    let mut slaves: HashMap<SocketAddrV4, String> = HashMap::new();
+
+    // Tilstanden slavene har fått, slik at bare det som har endret seg siden sendes
+    let mut broadcasted_state = master_system_state.clone();
+    let snapshot_ticker = cbc::tick(config.network.snapshot_interval);
 
     loop {
         select! {
+            recv(exit_channel_rx) -> new_master => {
+                if let Ok(Some(new_master)) = new_master {
+                    hand_over_state(new_master, &master_system_state, &version_id, &config);
+                }
+                info!("Stopper masterserveren.");
+                break;
+            },
             recv(host.receive_channel()) -> message => {
-                let (address, recieved_elevator_states) = message.unwrap();
-                slave_addresses.insert(address);
+                let (address, update) = message.unwrap();
+                let available_elevators_before = available_elevators(&master_system_state);
+
+                let hall_requests_changed = match update {
+                    StateUpdate::Snapshot(slave_state) => {
+                        // Heiser i en bygning med et annet antall etasjer kan ikke være med
+                        if slave_state.number_of_floors() != master_system_state.number_of_floors() {
+                            warn!(
+                                "Avviser {} med {} etasjer, systemet har {}.",
+                                slave_state.name,
+                                slave_state.number_of_floors(),
+                                master_system_state.number_of_floors()
+                            );
+                            connections.remove(&address);
+                            host.disconnect(address, format!(
+                                "Heisen har {} etasjer, men systemet har {}.",
+                                slave_state.number_of_floors(),
+                                master_system_state.number_of_floors()
+                            ));
+                            continue;
+                        }
+                        slaves.insert(address, slave_state.name.clone());
+                        pending_elevators.remove(&slave_state.name);
+
+                        info!("Master mottok tilstanden til slave:\n{}", slave_state);
+                        merge_slave_snapshot(&mut master_system_state, slave_state)
+                    },
+                    StateUpdate::Delta(delta) => {
+                        // Endringer gir bare mening for masteren når den har fått hele tilstanden til slaven
+                        if slaves.get(&address) != Some(&delta.origin) {
+                            debug!("Ignorerer endringer fra {} før tilstanden dens er mottatt.", delta.origin);
+                            continue;
+                        }
 
-                info!("Master mottok melding fra slave:\n{}", recieved_elevator_states);
+                        debug!("Master mottok {} endringer fra {}.", delta.changes.len(), delta.origin);
+                        merge_slave_delta(&mut master_system_state, delta)
+                    },
+                };
 
-                // Legg til nye heiser
-                for elevator_state in recieved_elevator_states.elevators.values() {
-                    master_system_state.elevators.insert(recieved_elevator_states.name.clone(), elevator_state.clone());
+                // Fordel bestillingene på nytt når det kommer nye bestillinger, eller en heis blir ute av drift eller kommer i drift igjen
+                let available_elevators_after = available_elevators(&master_system_state);
+                for name in available_elevators_before.symmetric_difference(&available_elevators_after) {
+                    info!("{name} er {}.", if available_elevators_after.contains(name) { "i drift" } else { "ute av drift" });
+                }
+                if hall_requests_changed || available_elevators_before != available_elevators_after {
+                    master_system_state.reassign_requests(assigner.as_mut());
                 }
 
-                if recieved_elevator_states.iteration - master_system_state.iteration == 1 {
-                     // Ta imot nye og slett fullførte bestillinger
-                    for (floor, received_request) in recieved_elevator_states.hall_requests.iter().enumerate() {
-                        let master_request = master_system_state.hall_requests[floor].clone();
-
-                        match (&received_request.up, &master_request.up) {
-                            (HallRequestState::Requested, HallRequestState::Inactive) => master_system_state.assign_request(floor as u8, Direction::Up),
-                            (HallRequestState::Inactive, HallRequestState::Assigned(_)) => master_system_state.hall_requests[floor].up = HallRequestState::Inactive,
-                            _ => {},
+                // Informere alle slaver om nye bestillinger
+                broadcast_changes(&host, &connections, &mut master_system_state, &mut broadcasted_state, &version_id);
+            },
+            recv(host.event_channel()) -> event => {
+                match event.unwrap() {
+                    ConnectionEvent::Connected(address) => {
+                        debug!("Slave koblet til fra {address}.");
+                        connections.insert(address);
+
+                        // En ny slave får hele tilstanden, og deretter bare endringene
+                        host.send_channel()
+                            .send((address, StateUpdate::Snapshot(broadcasted_state.clone())))
+                            .unwrap();
+                    },
+                    ConnectionEvent::Disconnected(address) => {
+                        connections.remove(&address);
+                        let Some(name) = slaves.remove(&address) else {
+                            continue;
+                        };
+                        // En master som har gitt fra seg tilstanden sin har ingen heis
+                        if !master_system_state.elevators.contains_key(&name) {
+                            continue;
                         }
 
-                        match (&received_request.down, &master_request.down) {
-                            (HallRequestState::Requested, HallRequestState::Inactive) => master_system_state.assign_request(floor as u8, Direction::Down),
-                            (HallRequestState::Inactive, HallRequestState::Assigned(_)) => master_system_state.hall_requests[floor].down = HallRequestState::Inactive,
-                            _ => {},
-                        }
-                    }
+                        warn!("Mistet tilkoblingen til {name}, fordeler bestillingene dens på nytt.");
+                        master_system_state.remove_elevator(&name, assigner.as_mut());
+
+                        broadcast_changes(&host, &connections, &mut master_system_state, &mut broadcasted_state, &version_id);
+                    },
+                }
+            },
+            recv(peer_events) -> event => {
+                // Slaven kan ha mistet nettverket selv om TCP-forbindelsen ikke er brutt ennå
+                let PeerEvent::PeerLost(name) = event.unwrap() else {
+                    continue;
+                };
+                if !master_system_state.elevators.contains_key(&name) {
+                    continue;
                 }
 
-                master_system_state.iteration += 1;
+                warn!("{name} har sluttet å annonsere, fordeler bestillingene dens på nytt.");
+                master_system_state.remove_elevator(&name, assigner.as_mut());
 
-                // Informere alle slaver om nye bestillinger
-                for slave_address in &slave_addresses {
-                    host.send_channel().send((*slave_address, master_system_state.to_owned())).unwrap();
-                }
-            }
+                broadcast_changes(&host, &connections, &mut master_system_state, &mut broadcasted_state, &version_id);
+            },
+            recv(pending_deadline) -> _ => {
+                pending_deadline = cbc::never();
+                remove_pending_elevators(&mut master_system_state, &mut pending_elevators, assigner.as_mut());
+
+                broadcast_changes(&host, &connections, &mut master_system_state, &mut broadcasted_state, &version_id);
+            },
+            recv(snapshot_ticker) -> _ => {
+                // Slaver som har gått glipp av en endring tar den igjen her
+                broadcast(&host, &connections, StateUpdate::Snapshot(broadcasted_state.clone()));
+                continue;
+            },
         }
 
-        if let Err(e) = save_state_to_file(&master_system_state, "backup.json") {
+        if let Err(e) = backup.save(&master_system_state) {
             error!("klarte ikke lagre backup: {e}");
         }
     }
 }
 
-pub fn send_state_to_maser(
-    client: &Client<SystemState>,
-    name: String,
-    mut system_state: SystemState,
-    local_elevator_state: ElevatorState,
+/// Sender tilstanden til masteren som tar over, slik at den får med seg alle bestillingene. Den sendes
+/// under navnet masteren teller endringene sine med, så den nye masteren ikke tar den for en heis.
+fn hand_over_state(new_master: SocketAddrV4, master_system_state: &SystemState, version_id: &str, config: &Config) {
+    info!("Gir tilstanden videre til masteren på {new_master}.");
+
+    let client = match Client::new_tcp_client(new_master.ip().octets(), new_master.port(), version_id.to_string(), config.network.codec) {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Klarte ikke gi tilstanden videre til {new_master}: {e}");
+            return;
+        }
+    };
+
+    let handed_over_state = SystemState {
+        name: version_id.to_string(),
+        ..master_system_state.clone()
+    };
+    client.send(StateUpdate::Snapshot(handed_over_state));
+}
+
+/// Regner heiser som ikke annonserer seg som ute av drift, bortsett fra nodens egen heis.
+/// Gir navnene på heisene, som er i drift igjen når de sender tilstanden sin.
+fn mark_unseen_elevators(master_system_state: &mut SystemState, alive_peers: &HashSet<String>) -> HashSet<String> {
+    let mut unseen_elevators = HashSet::new();
+
+    for (name, elevator_state) in master_system_state.elevators.iter_mut() {
+        if *name == master_system_state.name || alive_peers.contains(name) {
+            continue;
+        }
+
+        info!("{name} annonserer seg ikke, og får ikke bestillinger før den melder seg.");
+        elevator_state.state = State::OutOfOrder;
+        unseen_elevators.insert(name.clone());
+    }
+
+    unseen_elevators
+}
+
+/// Fjerner heisene som fortsatt ikke har meldt seg, og fordeler bestillingene på nytt.
+fn remove_pending_elevators(
+    master_system_state: &mut SystemState,
+    pending_elevators: &mut HashSet<String>,
+    assigner: &mut dyn Assigner,
 ) {
+    for name in pending_elevators.drain() {
+        warn!("{name} meldte seg ikke, fjerner den.");
+        master_system_state.elevators.remove(&name);
+    }
+    master_system_state.reassign_requests(assigner);
+}
+
+/// Tar med heisen til slaven og bestillingene den vet om. Gir sann dersom noen etasjebestillinger endret seg.
+fn merge_slave_snapshot(master_system_state: &mut SystemState, slave_state: SystemState) -> bool {
+    // En tilstand masteren allerede har sett alt i er en forsinket eller duplisert kopi,
+    // og kan inneholde en eldre heistilstand enn den masteren har
+    if matches!(slave_state.version.compare(&master_system_state.version), Causality::Before | Causality::Equal) {
+        debug!("Ignorerer utdatert tilstand fra {}.", slave_state.name);
+        return false;
+    }
+
+    if let Some(elevator_state) = slave_state.elevators.get(&slave_state.name) {
+        master_system_state.elevators.insert(slave_state.name.clone(), elevator_state.clone());
+    }
+
+    // Ta imot nye og slett fullførte bestillinger. Versjonsnumrene sørger for at nyere
+    // bestillinger aldri overskrives av eldre, også når meldinger kommer i feil rekkefølge
+    let hall_requests_changed = master_system_state.merge_hall_requests(&slave_state.hall_requests);
+    master_system_state.version.merge(&slave_state.version);
+
+    hall_requests_changed
+}
+
+/// Legger inn endringene fra en slave. Gir sann dersom noen etasjebestillinger endret seg.
+fn merge_slave_delta(master_system_state: &mut SystemState, delta: Delta) -> bool {
+    if delta.version <= master_system_state.version.get(&delta.origin) {
+        debug!("Ignorerer utdaterte endringer fra {}.", delta.origin);
+        return false;
+    }
+    master_system_state.version.observe(&delta.origin, delta.version);
+
+    let mut hall_requests_changed = false;
+    for change in &delta.changes {
+        match change.elevator_name() {
+            // En slave kan bare endre sin egen heis
+            Some(name) if name != delta.origin => continue,
+            Some(_) => {
+                state_sync::apply(master_system_state, change, false);
+            }
+            None => hall_requests_changed |= state_sync::apply(master_system_state, change, false),
+        }
+    }
+
+    hall_requests_changed
+}
+
+fn available_elevators(system_state: &SystemState) -> HashSet<String> {
     system_state
         .elevators
-        .insert(name, local_elevator_state);
-    system_state.iteration += 1;
-    client.sender().send(system_state).unwrap();
+        .iter()
+        .filter(|(_, elevator_state)| elevator_state.is_available())
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Sender slavene det som har endret seg siden forrige gang, som én melding.
+fn broadcast_changes(
+    host: &Host<StateUpdate>,
+    connections: &HashSet<SocketAddrV4>,
+    master_system_state: &mut SystemState,
+    broadcasted_state: &mut SystemState,
+    version_id: &str,
+) {
+    let changes = state_sync::diff(broadcasted_state, master_system_state);
+    if changes.is_empty() {
+        return;
+    }
+
+    master_system_state.version.increment(version_id);
+    *broadcasted_state = master_system_state.clone();
+
+    let delta = Delta {
+        origin: version_id.to_string(),
+        version: master_system_state.version.get(version_id),
+        changes,
+    };
+    broadcast(host, connections, StateUpdate::Delta(delta));
+}
+
+fn broadcast(
+    host: &Host<StateUpdate>,
+    connections: &HashSet<SocketAddrV4>,
+    update: StateUpdate,
+) {
+    for slave_address in connections {
+        host.send_channel()
+            .send((*slave_address, update.clone()))
+            .unwrap();
+    }
+}
+
+/// Kjører en node uten heis som kun kan være master. Den har høyere prioritet enn slavene i valget.
+pub fn start_master_server(name: String, config: &Config) {
+    let number_of_floors = config.number_of_floors;
+    let backup_path = backup_path(&config.backup_directory, Role::Master, &name);
+
+    let election = Election::init(name.clone(), MASTER_NODE_PRIORITY, number_of_floors, &config.network);
+    let mut master_server: Option<MasterServer> = None;
+
+    loop {
+        let Ok(event) = election.event_channel().recv() else {
+            error!("Valget har stoppet, og masteren kan ikke nås av andre noder.");
+            exit(1);
+        };
+
+        match event {
+            ElectionEvent::BecomeMaster => {
+                // Load state from backup if available
+                let master_system_state = load_backup(&backup_path, number_of_floors)
+                    .unwrap_or_else(|| SystemState::new(name.clone(), number_of_floors));
+
+                let server = MasterServer::start(
+                    master_system_state,
+                    config.clone(),
+                    backup_path.clone(),
+                    election.subscribe_peers(),
+                    election.alive_peers(),
+                );
+                election.set_master_port(Some(server.port()));
+                master_server = Some(server);
+            }
+            ElectionEvent::StepDown(new_master) => {
+                if let Some(server) = master_server.take() {
+                    server.hand_over(new_master);
+                }
+                election.set_master_port(None);
+            }
+            ElectionEvent::DuplicateNode(address) => refuse_duplicate_node(&name, address),
+            _ => {}
+        }
+    }
+}
+
+/// To noder med samme id ville overskrevet hverandres tilstand, så den nyeste av dem avslutter.
+fn refuse_duplicate_node(name: &str, address: SocketAddrV4) -> ! {
+    error!("Noden på {address} bruker allerede id-en {name}. Start med et annet --name.");
+    exit(1);
+}
+
+/// Leser backupen i `path`, og ser bort fra den dersom den mangler, er skadet eller har feil antall etasjer.
+fn load_backup(path: &Path, number_of_floors: u8) -> Option<SystemState> {
+    match load_state_from_file(path) {
+        Ok(state) if state.number_of_floors() != number_of_floors => {
+            warn!("Backup has {} floors, expected {number_of_floors}. Ignoring it.", state.number_of_floors());
+            None
+        }
+        Ok(state) => {
+            info!("Loaded backup from {}.", path.display());
+            Some(state)
+        }
+        Err(BackupError::Missing) => {
+            info!("No backup found at {}.", path.display());
+            None
+        }
+        Err(e) => {
+            error!("Klarte ikke lese backup, starter uten: {e}");
+            None
+        }
+    }
+}
+
+/// Sender master det som har endret seg siden sist, sammen med den nyeste tilstanden til heisen.
+fn send_changes_to_master(
+    client: &ReconnectingClient<StateUpdate>,
+    system_state: &mut SystemState,
+    synced_state: &mut SystemState,
+    local_elevator_state: &ElevatorState,
+) {
+    system_state.set_local_elevator_state(local_elevator_state);
+
+    let changes = state_sync::diff(synced_state, system_state);
+    if changes.is_empty() {
+        return;
+    }
+
+    let name = system_state.name.clone();
+    system_state.version.increment(&name);
+    *synced_state = system_state.clone();
+
+    let delta = Delta {
+        version: system_state.version.get(&name),
+        origin: name,
+        changes,
+    };
+    client.sender().send(StateUpdate::Delta(delta)).unwrap();
+}
+
+/// Sender hele tilstanden til master, slik at den får med seg alt den kan ha gått glipp av.
+fn send_snapshot_to_master(
+    client: &ReconnectingClient<StateUpdate>,
+    system_state: &mut SystemState,
+    synced_state: &mut SystemState,
+    local_elevator_state: &ElevatorState,
+) {
+    system_state.set_local_elevator_state(local_elevator_state);
+
+    // Versjonen økes alltid, slik at master tar imot tilstanden selv om den har fjernet heisen siden sist
+    let name = system_state.name.clone();
+    system_state.version.increment(&name);
+    *synced_state = system_state.clone();
+
+    client.sender().send(StateUpdate::Snapshot(system_state.clone())).unwrap();
 }
 
 /// Kobler opp til en master tjener. Sender bestillingsforespørsler og utfører mottatte bestillinger.
-pub fn start_slave_client(
-    name: Option<String>,
-    elevio_elevator: &elevio::elev::Elevator,
+/// Blir selv master dersom den vinner valget.
+pub fn start_slave_client<E: ElevatorIo>(
+    name: String,
+    config: &Config,
+    elevator_io: &E,
     elevator_command_tx: cbc::Sender<Requests>,
     elevator_event_rx: cbc::Receiver<ElevatorEvent>,
 ) {
-    let rx_channels = inputs::get_input_channels(&elevio_elevator);
-
-    let advertiser = Advertiser::init(0u16);
+    let number_of_floors = config.number_of_floors;
+    let rx_channels = elevator_io.input_channels(config.elevator.poll_period);
+
+    let slave_backup_path = backup_path(&config.backup_directory, Role::Slave, &name);
+    let backup = load_backup(&slave_backup_path, number_of_floors);
+    let mut slave_backup = BackupWriter::new(slave_backup_path);
+
+    // Blir slaven master, lagres mastertilstanden i en egen fil
+    let master_backup_path = backup_path(&config.backup_directory, Role::Master, &name);
+
+    let election = Election::init(name.clone(), SLAVE_NODE_PRIORITY, number_of_floors, &config.network);
+    let mut master_server: Option<MasterServer> = None;
+
+    // Klienten kobler seg til adressen til masteren valget sist fant, og kobler til på nytt når den mister tilkoblingen
+    let master_address: Arc<Mutex<Option<SocketAddrV4>>> = Arc::new(Mutex::new(None));
+    let client: ReconnectingClient<StateUpdate> = {
+        let master_address = Arc::clone(&master_address);
+        ReconnectingClient::new(
+            move || *master_address.lock().unwrap(),
+            config.network.queue_policy,
+            name.clone(),
+            config.network.codec,
+        )
+    };
+    let mut connected_to_master = false;
+    // Stopper valget, kjører heisen videre alene
+    let mut election_events = election.event_channel().clone();
 
     info!("Leter etter en master...");
-    let (master_address, master_port) = advertiser.receive_channel().recv().unwrap();
-    info!("Fant en master: {master_address} {master_port}");
 
-    let client: Client<SystemState> =
-        Client::new_tcp_client(master_address.ip().octets(), master_port).unwrap();
-    info!("Koblet til master!");
+    let mut local_elevator_state = ElevatorState::new(number_of_floors);
 
-    // Bruk et tilfeldig dyr som id dersom navn ikke er spesifisert:)
-    let name = name.unwrap_or(petname::petname(1, "").unwrap());
+    // Heisen melder seg ikke til master før den har funnet en etasje ved oppstart
+    let mut elevator_ready = false;
 
-    let mut local_elevator_state = ElevatorState {
-        state: State::Idle,
-        cab_requests: [false; 4],
-        direction: Direction::Up,
-        floor: 0,
-    };
+    let mut system_state = SystemState::new(name.clone(), number_of_floors);
+    // Det master vet om tilstanden, slik at bare det som har endret seg siden sendes
+    let mut synced_state = SystemState::new(name.clone(), number_of_floors);
+    let snapshot_ticker = cbc::tick(config.network.snapshot_interval);
 
-    let mut system_state = SystemState {
-        name: name.clone(),
-        ..Default::default()
-    };
+    // Cab-bestillingene fra forrige gang heisen kjørte tas før noe annet, siden ingen andre vet om dem
+    if let Some(local_backup) = backup.and_then(|mut state| state.elevators.remove(&name)) {
+        info!("Gjenoppretter cab-bestillingene til {name} fra backup.");
+        local_elevator_state.cab_requests = local_backup.cab_requests;
+    }
+    system_state.set_local_elevator_state(&local_elevator_state);
+
+    let requests = system_state.requests_for_local_elevator();
+    sync_call_lights(elevator_io, &requests);
+    elevator_command_tx.send(requests).unwrap();
 
     loop {
         cbc::select! {
             recv(elevator_event_rx) -> elevator_event => {
                 let elevator_event = elevator_event.unwrap();
+                let was_ready = std::mem::replace(&mut elevator_ready, true);
 
                 // Oppdater tilstand til lokal heis
                 local_elevator_state.floor = elevator_event.floor;
                 local_elevator_state.direction = elevator_event.direction;
                 local_elevator_state.state = elevator_event.state;
 
-                // Marker ordre i etasje som fullførte
-                local_elevator_state.cab_requests[elevator_event.floor as usize] = false;
+                // Marker ordre i etasje som fullførte. Bestillinger er bare utført når døra åpner,
+                // ikke når heisen kjører forbi eller stopper mellom to etasjer
+                if elevator_event.state == State::DoorOpen {
+                    local_elevator_state.cab_requests[elevator_event.floor as usize] = false;
 
-                if elevator_event.direction != Direction::Down {
-                    debug!("Cleared up.");
-                    system_state.hall_requests[elevator_event.floor as usize].up = HallRequestState::Inactive;
-                }
-                if elevator_event.direction != Direction::Up {
-                    debug!("Cleared down.");
-                    system_state.hall_requests[elevator_event.floor as usize].down = HallRequestState::Inactive;
+                    if elevator_event.direction != Direction::Down {
+                        debug!("Cleared up.");
+                        system_state.hall_requests[elevator_event.floor as usize].up.complete();
+                    }
+                    if elevator_event.direction != Direction::Up {
+                        debug!("Cleared down.");
+                        system_state.hall_requests[elevator_event.floor as usize].down.complete();
+                    }
                 }
 
                 // Send den oppdaterte ordrelisten til heiskontrolleren
                 if let Some(requests) = system_state.requests_for_elevator(&name) {
                     elevator_command_tx.send(requests).unwrap();
                 }
-                // Informer master om den nye tilstanden
-                send_state_to_maser(&client, name.clone(), system_state.clone(), local_elevator_state.clone());
+                if !connected_to_master {
+                    system_state.set_local_elevator_state(&local_elevator_state);
+                    sync_call_lights(elevator_io, &system_state.requests_for_local_elevator());
+                }
+                // Informer master om den nye tilstanden. Første gang sendes hele tilstanden, siden master ikke kjenner heisen ennå
+                if was_ready {
+                    send_changes_to_master(&client, &mut system_state, &mut synced_state, &local_elevator_state);
+                } else {
+                    send_snapshot_to_master(&client, &mut system_state, &mut synced_state, &local_elevator_state);
+                }
             },
             recv(rx_channels.call_button_rx) -> call_button => {
                 let call_button = call_button.unwrap();
@@ -166,39 +609,357 @@ pub fn start_slave_client(
                 let floor = call_button.floor as usize;
                 let hall_request = &mut system_state.hall_requests[floor];
 
+                // Uten master tar heisen bestillingen selv, og master får beskjed om den senere
+                let new_hall_request_state = if connected_to_master {
+                    HallRequestState::Requested
+                } else {
+                    HallRequestState::Assigned(name.clone())
+                };
+
                 // Legg inn bestilling på etasje
                 match call_button.call {
-                    HALL_UP if hall_request.up   == HallRequestState::Inactive => hall_request.up = HallRequestState::Requested,
-                    HALL_DOWN if hall_request.down == HallRequestState::Inactive => hall_request.down = HallRequestState::Requested,
+                    HALL_UP => hall_request.up.press(new_hall_request_state),
+                    HALL_DOWN => hall_request.down.press(new_hall_request_state),
                     CAB => local_elevator_state.cab_requests[floor] = true,
                     _ => {},
                 }
 
                 // Informer master om den nye tilstanden
-                send_state_to_maser(&client, name.clone(), system_state.clone(), local_elevator_state.clone());
+                if elevator_ready {
+                    send_changes_to_master(&client, &mut system_state, &mut synced_state, &local_elevator_state);
+                }
                 system_state.elevators.insert(name.clone(), local_elevator_state.clone());
-                client.sender().send(system_state.clone()).unwrap();
+
+                if !connected_to_master {
+                    let requests = system_state.requests_for_local_elevator();
+                    sync_call_lights(elevator_io, &requests);
+                    elevator_command_tx.send(requests).unwrap();
+                }
 
             },
+            recv(election_events) -> event => {
+                let Ok(event) = event else {
+                    error!("Valget har stoppet, heisen kjører videre uten master.");
+                    election_events = cbc::never();
+                    drop(master_server.take());
+                    *master_address.lock().unwrap() = None;
+                    client.reconnect();
+                    continue;
+                };
+
+                match event {
+                    ElectionEvent::BecomeMaster => {
+                        // Bygg opp tilstanden fra siste kopi mottatt fra forrige master
+                        let server = MasterServer::start(
+                            system_state.clone(),
+                            config.clone(),
+                            master_backup_path.clone(),
+                            election.subscribe_peers(),
+                            election.alive_peers(),
+                        );
+                        election.set_master_port(Some(server.port()));
+                        master_server = Some(server);
+                    },
+                    ElectionEvent::StepDown(new_master) => {
+                        if let Some(server) = master_server.take() {
+                            server.hand_over(new_master);
+                        }
+                        election.set_master_port(None);
+                    },
+                    ElectionEvent::MasterFound(new_master_address) => {
+                        info!("Fant en master: {new_master_address}");
+                        let previous_address = master_address.lock().unwrap().replace(new_master_address);
+
+                        // Tilkoblingen til en gammel master brytes, siden den ikke nødvendigvis merker at masteren er borte
+                        if previous_address != Some(new_master_address) {
+                            client.reconnect();
+                        }
+                    },
+                    ElectionEvent::MasterLost => {
+                        warn!("Mistet kontakt med master.");
+                        *master_address.lock().unwrap() = None;
+                        client.reconnect();
+                    },
+                    ElectionEvent::DuplicateNode(address) => {
+                        elevator_io.motor_direction(Direction::Stopped);
+                        refuse_duplicate_node(&name, address);
+                    },
+                }
+            },
+            recv(client.event_channel()) -> event => {
+                match event.unwrap() {
+                    ConnectionEvent::Connected(address) => {
+                        info!("Koblet til master på {address}!");
+                        connected_to_master = true;
+                        if elevator_ready {
+                            send_snapshot_to_master(&client, &mut system_state, &mut synced_state, &local_elevator_state);
+                        }
+                    },
+                    ConnectionEvent::Disconnected(address) => {
+                        warn!("Tilkoblingen til master på {address} ble brutt.");
+                        connected_to_master = false;
+
+                        // Bestillinger som ingen heis tar seg av nå, tar heisen selv
+                        if system_state.take_over_orphaned_calls(&election.alive_peers()) {
+                            info!("Tar over bestillinger som ingen andre heiser tar seg av.");
+                        }
+                        let requests = system_state.requests_for_local_elevator();
+                        sync_call_lights(elevator_io, &requests);
+                        elevator_command_tx.send(requests).unwrap();
+                    },
+                }
+            },
+            recv(snapshot_ticker) -> _ => {
+                // Master tar igjen endringer den har gått glipp av
+                if connected_to_master && elevator_ready {
+                    send_snapshot_to_master(&client, &mut system_state, &mut synced_state, &local_elevator_state);
+                }
+                continue;
+            },
             recv(client.receiver()) -> message => {
-                let (_, master_state) = message.unwrap();
+                let (_, update) = message.unwrap();
 
-                system_state = master_state;
-                system_state.set_local_elevator_state(&local_elevator_state);
+                match update {
+                    StateUpdate::Snapshot(master_state) => {
+                        if master_state.number_of_floors() != number_of_floors {
+                            warn!("Master har {} etasjer, men heisen har {number_of_floors}.", master_state.number_of_floors());
+                            continue;
+                        }
+
+                        // Versjonen avgjør ikke om kopien er ny for slaven, siden den også teller med endringer
+                        // fra master som kan ha gått tapt på veien. Derfor sammenlignes innholdet.
+                        if state_sync::diff(&synced_state, &master_state).is_empty() {
+                            debug!("Tilstanden fra master er den slaven allerede har.");
+                            continue;
+                        }
+
+                        // Tilstanden fra master har masterens navn, så slaven må sette sitt eget tilbake.
+                        // Bestillinger slaven har trykket inn eller fullført som master ikke har fått med seg ennå, beholdes
+                        let local_hall_requests = std::mem::take(&mut system_state.hall_requests);
+                        let mut version = std::mem::take(&mut system_state.version);
+                        version.merge(&master_state.version);
+                        synced_state = SystemState {
+                            name: name.clone(),
+                            version,
+                            ..master_state
+                        };
+                        system_state = synced_state.clone();
+                        system_state.set_local_elevator_state(&local_elevator_state);
+                        system_state.merge_hall_requests(&local_hall_requests);
+
+                        info!("Received state from master:\n{system_state}");
+                    },
+                    StateUpdate::Delta(delta) => {
+                        if delta.version <= system_state.version.get(&delta.origin) {
+                            debug!("Ignorerer utdaterte endringer fra master.");
+                            continue;
+                        }
+
+                        for state in [&mut system_state, &mut synced_state] {
+                            state.version.observe(&delta.origin, delta.version);
+                            for change in &delta.changes {
+                                state_sync::apply(state, change, true);
+                            }
+                        }
+
+                        // Master har fjernet heisen, så den må meldes inn på nytt
+                        if delta.changes.contains(&StateChange::ElevatorRemoved { name: name.clone() }) {
+                            synced_state.elevators.remove(&name);
+                        }
+
+                        debug!("Mottok {} endringer fra master.", delta.changes.len());
+                    },
+                }
 
-                info!("Received state from master:\n{system_state}");
+                // Meld inn det master mangler, helt til master har tatt det med
+                if elevator_ready {
+                    send_changes_to_master(&client, &mut system_state, &mut synced_state, &local_elevator_state);
+                }
 
                 // Send den nye bestillingslista til heiskontrolleren og lyskontrolleren
                 if let Some(requests) = system_state.requests_for_elevator(&name) {
-                    sync_call_lights(&elevio_elevator, &requests);
+                    sync_call_lights(elevator_io, &requests);
                     elevator_command_tx.send(requests).unwrap();
                 }
 
             },
         }
 
-        if let Err(e) = save_state_to_file(&system_state, "backup.json") {
+        if let Err(e) = slave_backup.save(&system_state) {
             error!("Klarte ikke å lagre backup: {e}");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::Ipv4Addr;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    fn idle_elevator(floor: u8) -> ElevatorState {
+        ElevatorState {
+            direction: Direction::Stopped,
+            state: State::Idle,
+            floor,
+            cab_requests: vec![false; 4],
+        }
+    }
+
+    // Tilstanden en ny master starter med: sin egen heis, en heis som annonserer seg og en som ikke gjør det
+    fn state_at_master_start() -> (SystemState, HashSet<String>) {
+        let mut state = SystemState::new("heis-1".to_string(), 4);
+        state
+            .elevators
+            .insert("heis-1".to_string(), idle_elevator(0));
+        state
+            .elevators
+            .insert("heis-2".to_string(), idle_elevator(1));
+        state
+            .elevators
+            .insert("heis-3".to_string(), idle_elevator(3));
+        state.hall_requests[3]
+            .down
+            .press(HallRequestState::Assigned("heis-3".to_string()));
+
+        (state, HashSet::from(["heis-2".to_string()]))
+    }
+
+    #[test]
+    fn unseen_elevators_get_no_requests_at_master_start() {
+        let mut assigner = create_assigner(&Config::default());
+        let (mut state, alive_peers) = state_at_master_start();
+
+        let pending_elevators = mark_unseen_elevators(&mut state, &alive_peers);
+        assert_eq!(pending_elevators, HashSet::from(["heis-3".to_string()]));
+        assert!(state.elevators["heis-1"].is_available());
+        assert!(!state.elevators["heis-3"].is_available());
+
+        state.reassign_requests(assigner.as_mut());
+        assert!(state.hall_requests[3].down.is_assigned_to("heis-2"));
+
+        // Heisen er i drift igjen når den sender tilstanden sin
+        let mut slave_state = SystemState::new("heis-3".to_string(), 4);
+        slave_state
+            .elevators
+            .insert("heis-3".to_string(), idle_elevator(3));
+        slave_state.version.increment("heis-3");
+        merge_slave_snapshot(&mut state, slave_state);
+        assert!(state.elevators["heis-3"].is_available());
+    }
+
+    #[test]
+    fn changes_queued_while_disconnected_reach_the_master() {
+        let mut master_state = SystemState::new("master".to_string(), 4);
+
+        // Slaven trykker inn en bestilling mens den er uten master, og legger endringen i kø
+        let mut slave_state = SystemState::new("heis-2".to_string(), 4);
+        slave_state.set_local_elevator_state(&idle_elevator(1));
+        let mut synced_state = slave_state.clone();
+        slave_state.hall_requests[2]
+            .up
+            .press(HallRequestState::Assigned("heis-2".to_string()));
+        slave_state.version.increment("heis-2");
+        let queued_delta = Delta {
+            origin: "heis-2".to_string(),
+            version: slave_state.version.get("heis-2"),
+            changes: state_sync::diff(&synced_state, &slave_state),
+        };
+        synced_state = slave_state.clone();
+
+        // Når den kobler til sendes tilstanden først, og deretter køen
+        slave_state.version.increment("heis-2");
+        assert!(merge_slave_snapshot(&mut master_state, slave_state.clone()));
+        merge_slave_delta(&mut master_state, queued_delta);
+
+        assert!(master_state.hall_requests[2].up.is_active());
+        assert_eq!(
+            master_state.elevators["heis-2"],
+            synced_state.elevators["heis-2"]
+        );
+        assert_eq!(
+            master_state.version.get("heis-2"),
+            slave_state.version.get("heis-2")
+        );
+    }
+
+    #[test]
+    fn master_that_steps_down_hands_its_calls_over() {
+        let directory = std::env::temp_dir().join(format!(
+            "vertikale-magier-{}-overtakelse",
+            std::process::id()
+        ));
+        fs::create_dir_all(&directory).unwrap();
+        let config = Config::default();
+
+        let mut old_state = SystemState::new("heis-1".to_string(), 4);
+        old_state.hall_requests[2]
+            .up
+            .press(HallRequestState::Assigned("heis-1".to_string()));
+        old_state.version.increment("heis-1:master");
+
+        let old_master = MasterServer::start(
+            old_state,
+            config.clone(),
+            directory.join("gammel"),
+            cbc::never(),
+            HashSet::new(),
+        );
+        let new_master = MasterServer::start(
+            SystemState::new("master".to_string(), 4),
+            config.clone(),
+            directory.join("ny"),
+            cbc::never(),
+            HashSet::new(),
+        );
+        old_master.hand_over(SocketAddrV4::new(Ipv4Addr::LOCALHOST, new_master.port()));
+
+        // En slave som kobler til den nye masteren får bestillingen med i tilstanden
+        let deadline = Instant::now() + Duration::from_secs(3);
+        loop {
+            let slave: Client<StateUpdate> = Client::new_tcp_client(
+                [127, 0, 0, 1],
+                new_master.port(),
+                "heis-2".to_string(),
+                config.network.codec,
+            )
+            .unwrap();
+            let (_, update) = slave
+                .receiver()
+                .recv_timeout(Duration::from_secs(1))
+                .unwrap();
+            let StateUpdate::Snapshot(state) = update else {
+                panic!("Forventet hele tilstanden fra master");
+            };
+            if state.hall_requests[2].up.is_active() {
+                break;
+            }
+
+            assert!(
+                Instant::now() < deadline,
+                "Den nye masteren fikk ikke bestillingen"
+            );
+            sleep(Duration::from_millis(50));
+        }
+
+        drop(new_master);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn unseen_elevators_that_never_report_are_removed() {
+        let mut assigner = create_assigner(&Config::default());
+        let (mut state, alive_peers) = state_at_master_start();
+
+        let mut pending_elevators = mark_unseen_elevators(&mut state, &alive_peers);
+        remove_pending_elevators(&mut state, &mut pending_elevators, assigner.as_mut());
+
+        assert!(pending_elevators.is_empty());
+        assert!(!state.elevators.contains_key("heis-3"));
+        assert!(state.elevators.contains_key("heis-1"));
+        assert!(state.elevators.contains_key("heis-2"));
+        assert!(state.hall_requests[3].down.is_assigned_to("heis-2"));
+    }
+}
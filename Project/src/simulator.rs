@@ -0,0 +1,432 @@
+use crossbeam_channel as cbc;
+use driver_rust::elevio::elev::{CAB, DIRN_DOWN, DIRN_STOP, DIRN_UP, HALL_DOWN, HALL_UP};
+use log::{debug, info, warn};
+use std::io::{self, BufRead, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+// Hvor ofte bilens posisjon oppdateres
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+// Hvor langt fra en etasje (i etasjer) etasjesensoren slår inn
+const FLOOR_SENSOR_RANGE: f64 = 0.05;
+// Hvor lenge en knapp holdes inne når den trykkes fra skript
+const BUTTON_PRESS_DURATION: Duration = Duration::from_millis(200);
+
+/// Kommandoer som styrer simulatoren utenfra, enten fra stdin eller fra en kanal.
+#[derive(Debug, Clone, Copy)]
+pub enum SimulatorCommand {
+    PressButton { floor: u8, call: u8 },
+    SetStopButton(bool),
+    SetObstruction(bool),
+    PrintStatus,
+}
+
+#[derive(Debug)]
+struct Car {
+    number_of_floors: u8,
+    position: f64,
+    motor_direction: u8,
+    call_buttons: Vec<[Option<Instant>; 3]>,
+    call_button_lights: Vec<[bool; 3]>,
+    floor_indicator: u8,
+    door_light: bool,
+    stop_button_light: bool,
+    stop_button: bool,
+    obstruction: bool,
+}
+
+impl Car {
+    fn new(number_of_floors: u8, start_position: f64) -> Self {
+        // Bilen kan ikke starte utenfor sjakta
+        let top_floor = number_of_floors.saturating_sub(1) as f64;
+        let position = if start_position.is_nan() { 0.0 } else { start_position.clamp(0.0, top_floor) };
+        if position != start_position {
+            warn!("Startposisjonen {start_position} er utenfor sjakta (0 til {top_floor}), starter i {position}.");
+        }
+
+        Car {
+            number_of_floors,
+            position,
+            motor_direction: DIRN_STOP,
+            call_buttons: vec![[None; 3]; number_of_floors as usize],
+            call_button_lights: vec![[false; 3]; number_of_floors as usize],
+            floor_indicator: 0,
+            door_light: false,
+            stop_button_light: false,
+            stop_button: false,
+            obstruction: false,
+        }
+    }
+
+    fn floor_sensor(&self) -> Option<u8> {
+        let nearest_floor = self.position.round();
+
+        if (self.position - nearest_floor).abs() <= FLOOR_SENSOR_RANGE {
+            Some(nearest_floor as u8)
+        } else {
+            None
+        }
+    }
+
+    fn tick(&mut self, elapsed: Duration, travel_time: Duration) {
+        let distance = elapsed.as_secs_f64() / travel_time.as_secs_f64();
+        let top_floor = (self.number_of_floors - 1) as f64;
+
+        match self.motor_direction {
+            DIRN_UP => self.position = (self.position + distance).min(top_floor),
+            DIRN_DOWN => self.position = (self.position - distance).max(0.0),
+            _ => {}
+        }
+
+        let now = Instant::now();
+        for buttons in self.call_buttons.iter_mut() {
+            for button in buttons.iter_mut() {
+                if button.is_some_and(|released_at| released_at <= now) {
+                    *button = None;
+                }
+            }
+        }
+    }
+
+    fn execute(&mut self, command: SimulatorCommand) {
+        match command {
+            SimulatorCommand::PressButton { floor, call } => {
+                if floor >= self.number_of_floors || call > CAB {
+                    warn!("Ugyldig knapp: etasje {floor}, type {call}");
+                    return;
+                }
+
+                self.call_buttons[floor as usize][call as usize] =
+                    Some(Instant::now() + BUTTON_PRESS_DURATION);
+            }
+            SimulatorCommand::SetStopButton(pressed) => self.stop_button = pressed,
+            SimulatorCommand::SetObstruction(obstructed) => self.obstruction = obstructed,
+            SimulatorCommand::PrintStatus => println!("{}", self.status()),
+        }
+    }
+
+    /// Håndterer én 4-byte melding fra elevio-protokollen. Returnerer svaret dersom meldingen er en spørring.
+    fn handle_message(&mut self, message: [u8; 4]) -> Option<[u8; 4]> {
+        let [command, first, second, third] = message;
+
+        match command {
+            1 => self.motor_direction = first,
+            2 => {
+                if let Some(lights) = self.call_button_lights.get_mut(second as usize) {
+                    if let Some(light) = lights.get_mut(first as usize) {
+                        *light = third != 0;
+                    }
+                }
+            }
+            3 => self.floor_indicator = first,
+            4 => self.door_light = first != 0,
+            5 => self.stop_button_light = first != 0,
+            6 => {
+                let pressed = self
+                    .call_buttons
+                    .get(second as usize)
+                    .and_then(|buttons| buttons.get(first as usize))
+                    .is_some_and(|button| button.is_some());
+
+                return Some([6, pressed as u8, 0, 0]);
+            }
+            7 => {
+                return Some(match self.floor_sensor() {
+                    Some(floor) => [7, 1, floor, 0],
+                    None => [7, 0, 0, 0],
+                })
+            }
+            8 => return Some([8, self.stop_button as u8, 0, 0]),
+            9 => return Some([9, self.obstruction as u8, 0, 0]),
+            _ => warn!("Ukjent kommando fra driver: {message:?}"),
+        }
+
+        None
+    }
+
+    fn status(&self) -> String {
+        let mut status = String::new();
+
+        for floor in (0..self.number_of_floors).rev() {
+            let lights = self.call_button_lights[floor as usize];
+            let car = if self.floor_sensor() == Some(floor) {
+                "#"
+            } else if self.floor_sensor().is_none() && self.position.floor() == floor as f64 {
+                "^"
+            } else {
+                " "
+            };
+
+            status += &format!(
+                "  Etasje {} [{}] Opp: {} Ned: {} Intern: {}\n",
+                floor + 1,
+                car,
+                lights[HALL_UP as usize] as u8,
+                lights[HALL_DOWN as usize] as u8,
+                lights[CAB as usize] as u8,
+            );
+        }
+
+        status += &format!(
+            "  Posisjon: {:.2}, Motor: {}, Etasjelys: {}, Dør: {}, Stopplys: {}, Stopp: {}, Obstruksjon: {}",
+            self.position + 1.0,
+            match self.motor_direction {
+                DIRN_UP => "opp",
+                DIRN_DOWN => "ned",
+                _ => "stoppet",
+            },
+            self.floor_indicator + 1,
+            self.door_light as u8,
+            self.stop_button_light as u8,
+            self.stop_button as u8,
+            self.obstruction as u8,
+        );
+
+        status
+    }
+}
+
+/// Programvaresimulert heis som snakker samme TCP-protokoll som SimElevatorServer.
+pub struct Simulator {
+    command_channel_tx: cbc::Sender<SimulatorCommand>,
+}
+
+impl Simulator {
+    /// Starter simulatoren på gitt port. Bilen starter i `start_position` (i etasjer, kan være mellom to etasjer),
+    /// som flyttes inn i sjakta dersom den er utenfor. Gir feil dersom porten ikke kan brukes.
+    pub fn start(
+        port: u16,
+        number_of_floors: u8,
+        travel_time: Duration,
+        start_position: f64,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))?;
+
+        let car = Arc::new(Mutex::new(Car::new(number_of_floors, start_position)));
+        let (command_channel_tx, command_channel_rx) = cbc::unbounded::<SimulatorCommand>();
+
+        info!("Simulator lytter på port: {port}");
+
+        {
+            let car = Arc::clone(&car);
+            spawn(move || run_physics(car, travel_time, command_channel_rx));
+        }
+
+        {
+            let car = Arc::clone(&car);
+            spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+
+                    let car = Arc::clone(&car);
+                    spawn(move || serve_driver(car, stream));
+                }
+            });
+        }
+
+        Ok(Simulator { command_channel_tx })
+    }
+
+    pub fn command_channel(&self) -> &cbc::Sender<SimulatorCommand> {
+        &self.command_channel_tx
+    }
+
+    /// Leser skriptkommandoer fra stdin til stdin lukkes.
+    pub fn run_script_from_stdin(&self) {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let command = match words.as_slice() {
+                [] => continue,
+                ["sleep", milliseconds] => {
+                    match milliseconds.parse() {
+                        Ok(milliseconds) => sleep(Duration::from_millis(milliseconds)),
+                        Err(_) => warn!("Ugyldig varighet: {milliseconds}"),
+                    }
+                    continue;
+                }
+                ["press", call, floor] => {
+                    let call = match *call {
+                        "up" => HALL_UP,
+                        "down" => HALL_DOWN,
+                        "cab" => CAB,
+                        _ => {
+                            warn!("Ukjent knappetype: {call}");
+                            continue;
+                        }
+                    };
+                    let Ok(floor) = floor.parse::<u8>() else {
+                        warn!("Ugyldig etasje: {floor}");
+                        continue;
+                    };
+
+                    SimulatorCommand::PressButton { floor, call }
+                }
+                ["stop", "on"] => SimulatorCommand::SetStopButton(true),
+                ["stop", "off"] => SimulatorCommand::SetStopButton(false),
+                ["obstruction", "on"] => SimulatorCommand::SetObstruction(true),
+                ["obstruction", "off"] => SimulatorCommand::SetObstruction(false),
+                ["status"] => SimulatorCommand::PrintStatus,
+                _ => {
+                    warn!(
+                        "Ukjent kommando: '{line}'. Gyldige kommandoer er 'press <up|down|cab> <etasje>', \
+                         'stop <on|off>', 'obstruction <on|off>', 'status' og 'sleep <ms>'."
+                    );
+                    continue;
+                }
+            };
+
+            self.command_channel().send(command).unwrap();
+        }
+    }
+}
+
+fn run_physics(
+    car: Arc<Mutex<Car>>,
+    travel_time: Duration,
+    mut command_channel_rx: cbc::Receiver<SimulatorCommand>,
+) {
+    let mut last_tick = Instant::now();
+
+    loop {
+        cbc::select! {
+            recv(command_channel_rx) -> command => {
+                let Ok(command) = command else {
+                    command_channel_rx = cbc::never();
+                    continue;
+                };
+                car.lock().unwrap().execute(command);
+            },
+            default(TICK_INTERVAL) => {},
+        }
+
+        let now = Instant::now();
+        car.lock().unwrap().tick(now - last_tick, travel_time);
+        last_tick = now;
+    }
+}
+
+fn serve_driver(car: Arc<Mutex<Car>>, mut stream: TcpStream) {
+    debug!("Driver koblet til simulatoren.");
+
+    loop {
+        let mut message = [0; 4];
+        if stream.read_exact(&mut message).is_err() {
+            break;
+        }
+
+        let response = car.lock().unwrap().handle_message(message);
+
+        if let Some(response) = response {
+            if stream.write_all(&response).is_err() {
+                break;
+            }
+        }
+    }
+
+    debug!("Driver koblet fra simulatoren.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRAVEL_TIME: Duration = Duration::from_secs(2);
+
+    #[test]
+    fn port_in_use_is_an_error() {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        assert!(Simulator::start(port, 4, TRAVEL_TIME, 0.0).is_err());
+    }
+
+    #[test]
+    fn start_position_is_kept_inside_the_shaft() {
+        assert_eq!(Car::new(4, 1.5).position, 1.5);
+        assert_eq!(Car::new(4, 3.0).position, 3.0);
+        assert_eq!(Car::new(4, 7.0).position, 3.0);
+        assert_eq!(Car::new(4, -1.0).position, 0.0);
+        assert_eq!(Car::new(4, f64::NAN).position, 0.0);
+    }
+
+    #[test]
+    fn car_moves_between_floors_and_stops_at_the_end() {
+        let mut car = Car::new(4, 0.0);
+        assert_eq!(car.floor_sensor(), Some(0));
+
+        car.motor_direction = DIRN_UP;
+        car.tick(TRAVEL_TIME / 2, TRAVEL_TIME);
+        assert_eq!(car.floor_sensor(), None);
+        car.tick(TRAVEL_TIME / 2, TRAVEL_TIME);
+        assert_eq!(car.floor_sensor(), Some(1));
+
+        car.tick(TRAVEL_TIME * 10, TRAVEL_TIME);
+        assert_eq!(car.floor_sensor(), Some(3));
+
+        car.motor_direction = DIRN_DOWN;
+        car.tick(TRAVEL_TIME * 10, TRAVEL_TIME);
+        assert_eq!(car.floor_sensor(), Some(0));
+    }
+
+    fn query(driver: &mut TcpStream, message: [u8; 4]) -> [u8; 4] {
+        driver.write_all(&message).unwrap();
+        let mut response = [0; 4];
+        driver.read_exact(&mut response).unwrap();
+        response
+    }
+
+    // Går gjennom TCP slik elevio-driveren gjør
+    #[test]
+    fn driver_protocol_round_trip() {
+        let car = Arc::new(Mutex::new(Car::new(4, 2.0)));
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        {
+            let car = Arc::clone(&car);
+            spawn(move || serve_driver(car, listener.accept().unwrap().0));
+        }
+        let mut driver = TcpStream::connect(address).unwrap();
+
+        {
+            let mut car = car.lock().unwrap();
+            car.execute(SimulatorCommand::PressButton {
+                floor: 1,
+                call: HALL_DOWN,
+            });
+            car.execute(SimulatorCommand::SetObstruction(true));
+        }
+
+        assert_eq!(query(&mut driver, [7, 0, 0, 0]), [7, 1, 2, 0]);
+        assert_eq!(query(&mut driver, [6, HALL_DOWN, 1, 0]), [6, 1, 0, 0]);
+        assert_eq!(query(&mut driver, [6, HALL_UP, 1, 0]), [6, 0, 0, 0]);
+        assert_eq!(query(&mut driver, [8, 0, 0, 0]), [8, 0, 0, 0]);
+        assert_eq!(query(&mut driver, [9, 0, 0, 0]), [9, 1, 0, 0]);
+
+        // Utgangene har ikke svar, så en spørring etterpå viser at de er behandlet
+        for message in [
+            [1, DIRN_UP, 0, 0],
+            [2, CAB, 3, 1],
+            [3, 2, 0, 0],
+            [4, 1, 0, 0],
+            [5, 1, 0, 0],
+        ] {
+            driver.write_all(&message).unwrap();
+        }
+        assert_eq!(query(&mut driver, [8, 0, 0, 0]), [8, 0, 0, 0]);
+
+        let car = car.lock().unwrap();
+        assert_eq!(car.motor_direction, DIRN_UP);
+        assert!(car.call_button_lights[3][CAB as usize]);
+        assert_eq!(car.floor_indicator, 2);
+        assert!(car.door_light);
+        assert!(car.stop_button_light);
+    }
+}
@@ -0,0 +1,305 @@
+use serde::{Deserialize, Serialize};
+
+use crate::elevator_controller::{Direction, State};
+use crate::system_state::{ElevatorState, HallCall, SystemState};
+
+/// Meldingene master og slaver synkroniserer tilstanden med. Vanligvis sendes bare det som har
+/// endret seg. Hele tilstanden sendes når en slave kobler til, og med jevne mellomrom, slik at
+/// en node som har gått glipp av en endring tar den igjen.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StateUpdate {
+    Snapshot(SystemState),
+    Delta(Delta),
+}
+
+/// Endringer gjort av `origin`. `version` er versjonen til `origin` etter endringene, og brukes
+/// til å kjenne igjen forsinkede eller dupliserte meldinger.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delta {
+    pub origin: String,
+    pub version: u64,
+    pub changes: Vec<StateChange>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HallDirection {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StateChange {
+    HallRequest {
+        floor: u8,
+        direction: HallDirection,
+        call: HallCall,
+    },
+    /// Hvor heisen er og hva den gjør. Cab-bestillingene sendes hver for seg.
+    Elevator {
+        name: String,
+        direction: Direction,
+        state: State,
+        floor: u8,
+    },
+    CabRequest {
+        name: String,
+        floor: u8,
+        active: bool,
+    },
+    ElevatorRemoved {
+        name: String,
+    },
+}
+
+impl StateChange {
+    /// Heisen endringen gjelder. Etasjebestillinger gjelder ingen bestemt heis.
+    pub fn elevator_name(&self) -> Option<&str> {
+        match self {
+            StateChange::HallRequest { .. } => None,
+            StateChange::Elevator { name, .. }
+            | StateChange::CabRequest { name, .. }
+            | StateChange::ElevatorRemoved { name } => Some(name),
+        }
+    }
+}
+
+/// Finner endringene som gjør `old` om til `new`.
+pub fn diff(old: &SystemState, new: &SystemState) -> Vec<StateChange> {
+    let mut changes = Vec::new();
+
+    for (floor, (old_request, new_request)) in old.hall_requests.iter().zip(&new.hall_requests).enumerate() {
+        let calls = [
+            (HallDirection::Up, &old_request.up, &new_request.up),
+            (HallDirection::Down, &old_request.down, &new_request.down),
+        ];
+        for (direction, old_call, new_call) in calls {
+            if old_call != new_call {
+                changes.push(StateChange::HallRequest {
+                    floor: floor as u8,
+                    direction,
+                    call: new_call.clone(),
+                });
+            }
+        }
+    }
+
+    // Sorteres så endringene kommer i samme rekkefølge hver gang
+    let mut names: Vec<&String> = new.elevators.keys().collect();
+    names.sort();
+
+    for name in names {
+        let new_elevator = &new.elevators[name];
+        let old_elevator = old.elevators.get(name);
+        let empty_elevator = ElevatorState::new(new.number_of_floors());
+
+        let moved = old_elevator.is_none_or(|old_elevator| {
+            (old_elevator.direction, old_elevator.state, old_elevator.floor)
+                != (new_elevator.direction, new_elevator.state, new_elevator.floor)
+        });
+        if moved {
+            changes.push(StateChange::Elevator {
+                name: name.clone(),
+                direction: new_elevator.direction,
+                state: new_elevator.state,
+                floor: new_elevator.floor,
+            });
+        }
+
+        let old_cab_requests = &old_elevator.unwrap_or(&empty_elevator).cab_requests;
+        for (floor, (old_cab, new_cab)) in old_cab_requests.iter().zip(&new_elevator.cab_requests).enumerate() {
+            if old_cab != new_cab {
+                changes.push(StateChange::CabRequest {
+                    name: name.clone(),
+                    floor: floor as u8,
+                    active: *new_cab,
+                });
+            }
+        }
+    }
+
+    let mut removed: Vec<&String> = old
+        .elevators
+        .keys()
+        .filter(|name| !new.elevators.contains_key(*name))
+        .collect();
+    removed.sort();
+
+    for name in removed {
+        changes.push(StateChange::ElevatorRemoved { name: name.clone() });
+    }
+
+    changes
+}
+
+/// Legger en endring inn i `state`, og gir sann dersom tilstanden endret seg. Endringer fra master
+/// vinner når en bestilling har samme versjon, siden det er master som bestemmer hvilken heis som
+/// tar den. Endringer fra master på nodens egen heis ses bort fra, siden noden selv vet best.
+pub fn apply(state: &mut SystemState, change: &StateChange, from_master: bool) -> bool {
+    if from_master && change.elevator_name() == Some(state.name.as_str()) {
+        return false;
+    }
+    let number_of_floors = state.number_of_floors();
+
+    match change {
+        StateChange::HallRequest { floor, direction, call } => {
+            let Some(request) = state.hall_requests.get_mut(*floor as usize) else {
+                return false;
+            };
+            let local = match direction {
+                HallDirection::Up => &mut request.up,
+                HallDirection::Down => &mut request.down,
+            };
+
+            if from_master {
+                if call.version < local.version || call == local {
+                    return false;
+                }
+                *local = call.clone();
+                true
+            } else {
+                local.merge(call)
+            }
+        }
+        StateChange::Elevator { name, direction, state: elevator_state, floor } => {
+            let elevator = state
+                .elevators
+                .entry(name.clone())
+                .or_insert_with(|| ElevatorState::new(number_of_floors));
+            let previous = (elevator.direction, elevator.state, elevator.floor);
+
+            elevator.direction = *direction;
+            elevator.state = *elevator_state;
+            elevator.floor = *floor;
+
+            previous != (*direction, *elevator_state, *floor)
+        }
+        StateChange::CabRequest { name, floor, active } => {
+            let elevator = state
+                .elevators
+                .entry(name.clone())
+                .or_insert_with(|| ElevatorState::new(number_of_floors));
+            let Some(cab_request) = elevator.cab_requests.get_mut(*floor as usize) else {
+                return false;
+            };
+
+            let changed = *cab_request != *active;
+            *cab_request = *active;
+            changed
+        }
+        StateChange::ElevatorRemoved { name } => state.elevators.remove(name).is_some(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system_state::HallRequestState;
+
+    const FLOORS: u8 = 4;
+
+    fn elevator(floor: u8, state: State, cab_floors: &[usize]) -> ElevatorState {
+        let mut elevator = ElevatorState::new(FLOORS);
+        elevator.floor = floor;
+        elevator.state = state;
+        for cab_floor in cab_floors {
+            elevator.cab_requests[*cab_floor] = true;
+        }
+        elevator
+    }
+
+    fn old_state() -> SystemState {
+        let mut state = SystemState::new("heis-1".to_string(), FLOORS);
+        state
+            .elevators
+            .insert("heis-1".to_string(), elevator(0, State::Idle, &[]));
+        state
+            .elevators
+            .insert("heis-2".to_string(), elevator(3, State::DoorOpen, &[1]));
+        state
+            .elevators
+            .insert("heis-3".to_string(), elevator(2, State::Idle, &[]));
+        state.hall_requests[1].up.press(HallRequestState::Requested);
+        state.hall_requests[2]
+            .down
+            .press(HallRequestState::Assigned("heis-2".to_string()));
+        state
+    }
+
+    // Alle slags endringer: bestillinger inn og ut, heiser som flytter seg, kommer til og forsvinner
+    fn new_state() -> SystemState {
+        let mut state = old_state();
+        state.hall_requests[0].up.press(HallRequestState::Requested);
+        state.hall_requests[1].up.state = HallRequestState::Assigned("heis-1".to_string());
+        state.hall_requests[1].up.version += 2;
+        state.hall_requests[2].down.complete();
+        state.hall_requests[3]
+            .down
+            .press(HallRequestState::Assigned("heis-4".to_string()));
+
+        state
+            .elevators
+            .insert("heis-1".to_string(), elevator(1, State::Moving, &[3]));
+        state.elevators.get_mut("heis-2").unwrap().cab_requests[1] = false;
+        state.elevators.remove("heis-3");
+        state
+            .elevators
+            .insert("heis-4".to_string(), elevator(3, State::Idle, &[0, 2]));
+        state
+    }
+
+    fn apply_all(state: &mut SystemState, changes: &[StateChange], from_master: bool) -> bool {
+        changes.iter().fold(false, |changed, change| {
+            apply(state, change, from_master) | changed
+        })
+    }
+
+    #[test]
+    fn apply_of_diff_gives_new_state() {
+        let (old, new) = (old_state(), new_state());
+        let changes = diff(&old, &new);
+
+        let mut state = old.clone();
+        for change in &changes {
+            assert!(
+                apply(&mut state, change, false),
+                "Endringen gjorde ingenting: {change:?}"
+            );
+        }
+        assert_eq!(state, new);
+
+        // Samme endringer en gang til gjør ingenting
+        assert!(!apply_all(&mut state, &changes, false));
+        assert_eq!(state, new);
+        assert!(diff(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn apply_of_diff_from_master_keeps_own_elevator() {
+        let (old, new) = (old_state(), new_state());
+        let changes = diff(&old, &new);
+
+        let mut state = old.clone();
+        assert!(apply_all(&mut state, &changes, true));
+
+        let mut expected = new.clone();
+        expected
+            .elevators
+            .insert("heis-1".to_string(), old.elevators["heis-1"].clone());
+        assert_eq!(state, expected);
+    }
+
+    #[test]
+    fn older_hall_call_does_not_replace_newer() {
+        let (old, new) = (old_state(), new_state());
+        let mut state = new.clone();
+
+        // Bestillingene i `old` har lavere versjoner enn de i `new`
+        let hall_changes: Vec<StateChange> = diff(&new, &old)
+            .into_iter()
+            .filter(|change| change.elevator_name().is_none())
+            .collect();
+        assert!(!hall_changes.is_empty());
+        assert!(!apply_all(&mut state, &hall_changes, false));
+        assert_eq!(state.hall_requests, new.hall_requests);
+    }
+}
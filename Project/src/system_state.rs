@@ -1,16 +1,36 @@
+use log::error;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
 
-use crate::config::NUMBER_OF_FLOORS;
+use crate::assigner::Assigner;
 use crate::elevator_controller::{Direction, Request, Requests, State};
 use crate::hall_request_assigner as hra;
+use crate::version_vector::VersionVector;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ElevatorState {
     pub direction: Direction,
     pub state: State,
     pub floor: u8, // TOOD: Denne typen kan vel egentlig være usize?
-    pub cab_requests: [bool; NUMBER_OF_FLOORS],
+    pub cab_requests: Vec<bool>,
+}
+
+impl ElevatorState {
+    pub fn new(number_of_floors: u8) -> Self {
+        ElevatorState {
+            direction: Direction::Up,
+            state: State::Idle,
+            floor: 0,
+            cab_requests: vec![false; number_of_floors as usize],
+        }
+    }
+    // Heiser som er ute av drift skal ikke få nye bestillinger
+    pub fn is_available(&self) -> bool {
+        self.state != State::OutOfOrder
+    }
 }
 
 impl From<&ElevatorState> for hra::State {
@@ -27,7 +47,7 @@ impl From<&ElevatorState> for hra::State {
                 Direction::Stopped => hra::Direction::Stop,
                 Direction::Up => hra::Direction::Up,
             },
-            cab_requests: single_elevator_state.cab_requests,
+            cab_requests: single_elevator_state.cab_requests.clone(),
         }
     }
 }
@@ -45,35 +65,71 @@ impl fmt::Display for ElevatorState {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub enum HallRequestState {
+    #[default]
     Inactive,
     Requested,
     Assigned(String),
 }
 
-impl Default for HallRequestState {
-    fn default() -> Self {
-        Self::Inactive
+/// En etasjebestilling med et versjonsnummer som øker hver gang knappen trykkes inn eller
+/// bestillingen fullføres. Oddetall betyr aktiv og partall inaktiv, så den høyeste versjonen
+/// er alltid den nyeste, uansett hvilken rekkefølge meldingene kommer fram i.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct HallCall {
+    pub state: HallRequestState,
+    pub version: u64,
+}
+
+impl HallCall {
+    pub fn is_active(&self) -> bool {
+        self.version % 2 == 1
+    }
+    pub fn is_assigned_to(&self, name: &str) -> bool {
+        matches!(&self.state, HallRequestState::Assigned(id) if id == name)
+    }
+    // Knappen er trykket inn. En bestilling som allerede er aktiv forblir som den er.
+    pub fn press(&mut self, state: HallRequestState) {
+        if !self.is_active() {
+            self.version += 1;
+            self.state = state;
+        }
+    }
+    pub fn complete(&mut self) {
+        if self.is_active() {
+            self.version += 1;
+            self.state = HallRequestState::Inactive;
+        }
+    }
+    // Tar over den andre bestillingen dersom den er nyere. Gir sann om bestillingen endret seg.
+    pub fn merge(&mut self, other: &HallCall) -> bool {
+        if other.version <= self.version {
+            return false;
+        }
+
+        *self = other.clone();
+        true
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub struct HallRequest {
-    pub up: HallRequestState,
-    pub down: HallRequestState,
+    pub up: HallCall,
+    pub down: HallCall,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub struct SystemState {
     pub name: String,
     pub elevators: HashMap<String, ElevatorState>, //Liste over alle aktive heiser
-    pub hall_requests: [HallRequest; NUMBER_OF_FLOORS],
-    pub iteration: i32,
+    pub hall_requests: Vec<HallRequest>,
+    pub version: VersionVector,
 }
 
 impl fmt::Display for SystemState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Versjon: {}", self.version)?;
         writeln!(f, "Heiser:")?;
         for (id, elevator_state) in &self.elevators {
             writeln!(f, "  {id}:")?;
@@ -89,8 +145,11 @@ impl fmt::Display for SystemState {
 
             writeln!(
                 f,
-                "  Etasje {floor} - Ned: {:?}, Opp: {:?}",
-                hall_request.down, hall_request.up
+                "  Etasje {floor} - Ned: {:?} (v{}), Opp: {:?} (v{})",
+                hall_request.down.state,
+                hall_request.down.version,
+                hall_request.up.state,
+                hall_request.up.version
             )?;
         }
 
@@ -99,69 +158,380 @@ impl fmt::Display for SystemState {
 }
 
 impl SystemState {
-    // Velger beste heis for en bestilling
-    pub fn assign_request(&mut self, floor: u8, direction: Direction) {
-        match direction {
-            Direction::Up => self.hall_requests[floor as usize].up = HallRequestState::Requested,
-            Direction::Down => {
-                self.hall_requests[floor as usize].down = HallRequestState::Requested
+    pub fn new(name: String, number_of_floors: u8) -> Self {
+        SystemState {
+            name,
+            hall_requests: vec![Default::default(); number_of_floors as usize],
+            ..Default::default()
+        }
+    }
+    pub fn number_of_floors(&self) -> u8 {
+        self.hall_requests.len() as u8
+    }
+    // Tar med seg alle bestillinger fra `hall_requests` som er nyere enn de man har. Gir sann om noen endret seg.
+    pub fn merge_hall_requests(&mut self, hall_requests: &[HallRequest]) -> bool {
+        let mut changed = false;
+
+        for (local, received) in self.hall_requests.iter_mut().zip(hall_requests) {
+            changed |= local.up.merge(&received.up);
+            changed |= local.down.merge(&received.down);
+        }
+
+        changed
+    }
+    // Fjerner en heis og gir bestillingene dens til de andre heisene
+    pub fn remove_elevator(&mut self, name: &str, assigner: &mut dyn Assigner) {
+        if self.elevators.remove(name).is_some() {
+            self.reassign_requests(assigner);
+        }
+    }
+    // Fordeler alle aktive bestillinger på nytt mellom heisene som er i drift
+    pub fn reassign_requests(&mut self, assigner: &mut dyn Assigner) {
+        // Bestillinger hos heiser som er borte eller ute av drift må fordeles på nytt
+        for hall_request in self.hall_requests.iter_mut() {
+            for request in [&mut hall_request.up.state, &mut hall_request.down.state] {
+                if let HallRequestState::Assigned(id) = request {
+                    if !self.elevators.get(id).is_some_and(ElevatorState::is_available) {
+                        *request = HallRequestState::Requested;
+                    }
+                }
             }
-            _ => panic!("Tried to assign request with invalid direction"),
         }
 
-        let hall_requests = self.hall_requests.clone().map(|request| {
-            (
-                request.up != HallRequestState::Inactive,
-                request.down != HallRequestState::Inactive,
-            )
-        });
+        let hall_requests = self
+            .hall_requests
+            .iter()
+            .map(|request| (request.up.is_active(), request.down.is_active()))
+            .collect();
         let states = self
             .elevators
             .iter()
+            .filter(|(_, elevator_state)| elevator_state.is_available())
             .map(|(k, v)| (k.to_owned(), v.into()))
             .collect();
 
-        let assignments = hra::run_hall_request_assigner(hra::HallRequestsStates {
+        let assignments = match assigner.assign(&hra::HallRequestsStates {
             hall_requests,
             states,
-        })
-        .unwrap();
+        }) {
+            Ok(assignments) => assignments,
+            Err(error) => {
+                error!("Klarte ikke fordele bestillinger: {error}");
+                return;
+            }
+        };
 
         for (id, assigned_hall_requests) in assignments.iter() {
             for (floor, (up, down)) in assigned_hall_requests.iter().enumerate() {
                 if *up {
-                    self.hall_requests[floor].up = HallRequestState::Assigned(id.to_string());
+                    self.hall_requests[floor].up.state = HallRequestState::Assigned(id.to_string());
                 }
 
                 if *down {
-                    self.hall_requests[floor].down = HallRequestState::Assigned(id.to_string());
+                    self.hall_requests[floor].down.state =
+                        HallRequestState::Assigned(id.to_string());
                 }
             }
         }
     }
+    // Uten master tar den lokale heisen over aktive bestillinger som ikke er fordelt, eller som er gitt til
+    // en heis som ikke er blant `alive_elevators`. Gir sann om noen bestillinger ble tatt over.
+    pub fn take_over_orphaned_calls(&mut self, alive_elevators: &HashSet<String>) -> bool {
+        let mut taken_over = false;
+
+        for hall_request in self.hall_requests.iter_mut() {
+            for call in [&mut hall_request.up, &mut hall_request.down] {
+                let is_orphaned = match &call.state {
+                    HallRequestState::Requested => true,
+                    HallRequestState::Assigned(id) => *id != self.name && !alive_elevators.contains(id),
+                    HallRequestState::Inactive => false,
+                };
+
+                if call.is_active() && is_orphaned {
+                    call.state = HallRequestState::Assigned(self.name.clone());
+                    taken_over = true;
+                }
+            }
+        }
+
+        taken_over
+    }
     pub fn requests_for_elevator(&self, name: &String) -> Option<Requests> {
-        let mut requests = [Request {
-            cab: false,
-            hall_down: false,
-            hall_up: false,
-        }; NUMBER_OF_FLOORS];
+        let mut requests = vec![
+            Request {
+                cab: false,
+                hall_down: false,
+                hall_up: false,
+            };
+            self.hall_requests.len()
+        ];
 
         for (floor, cab_request) in self.elevators.get(name)?.cab_requests.iter().enumerate() {
             requests[floor].cab = *cab_request;
         }
 
         for (floor, hall_request) in self.hall_requests.iter().enumerate() {
-            requests[floor].hall_up = hall_request.up == HallRequestState::Assigned(name.clone());
-            requests[floor].hall_down =
-                hall_request.down == HallRequestState::Assigned(name.clone());
+            requests[floor].hall_up = hall_request.up.is_assigned_to(name);
+            requests[floor].hall_down = hall_request.down.is_assigned_to(name);
         }
 
-        return Some(requests);
+        Some(requests)
     }
     pub fn requests_for_local_elevator(&self) -> Requests {
-        self.requests_for_elevator(&self.name).unwrap_or(Default::default())
+        self.requests_for_elevator(&self.name)
+            .unwrap_or_else(|| vec![Default::default(); self.hall_requests.len()])
     }
     pub fn set_local_elevator_state(&mut self, local_elevator_state: &ElevatorState) {
         self.elevators.insert(self.name.clone(), local_elevator_state.clone());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assigner::create_assigner;
+    use crate::config::Config;
+
+    fn idle_elevator(floor: u8) -> ElevatorState {
+        ElevatorState {
+            direction: Direction::Stopped,
+            state: State::Idle,
+            floor,
+            cab_requests: vec![false; 4],
+        }
+    }
+
+    #[test]
+    fn removed_elevator_hands_its_requests_over() {
+        let mut assigner = create_assigner(&Config::default());
+        let mut state = SystemState::new(String::new(), 4);
+        state
+            .elevators
+            .insert("heis-1".to_string(), idle_elevator(0));
+        state
+            .elevators
+            .insert("heis-2".to_string(), idle_elevator(3));
+
+        state.hall_requests[3]
+            .down
+            .press(HallRequestState::Requested);
+        state.reassign_requests(assigner.as_mut());
+        assert!(state.hall_requests[3].down.is_assigned_to("heis-2"));
+
+        state.remove_elevator("heis-2", assigner.as_mut());
+        assert!(!state.elevators.contains_key("heis-2"));
+        assert!(state.hall_requests[3].down.is_assigned_to("heis-1"));
+    }
+
+    #[test]
+    fn out_of_order_elevator_gets_no_requests() {
+        let mut assigner = create_assigner(&Config::default());
+        let mut state = SystemState::new(String::new(), 4);
+        state
+            .elevators
+            .insert("heis-1".to_string(), idle_elevator(0));
+        state
+            .elevators
+            .insert("heis-2".to_string(), idle_elevator(3));
+        state.hall_requests[3]
+            .down
+            .press(HallRequestState::Requested);
+
+        state.elevators.get_mut("heis-2").unwrap().state = State::OutOfOrder;
+        state.reassign_requests(assigner.as_mut());
+        assert!(state.hall_requests[3].down.is_assigned_to("heis-1"));
+
+        // Tilbake i drift er heis 2 igjen nærmest
+        state.elevators.get_mut("heis-2").unwrap().state = State::Idle;
+        state.reassign_requests(assigner.as_mut());
+        assert!(state.hall_requests[3].down.is_assigned_to("heis-2"));
+    }
+
+    #[test]
+    fn requests_wait_while_no_elevator_is_available() {
+        let mut assigner = create_assigner(&Config::default());
+        let mut state = SystemState::new(String::new(), 4);
+        let mut elevator = idle_elevator(1);
+        elevator.state = State::OutOfOrder;
+        state.elevators.insert("heis-1".to_string(), elevator);
+
+        state.hall_requests[2].up.press(HallRequestState::Requested);
+        state.reassign_requests(assigner.as_mut());
+        assert_eq!(state.hall_requests[2].up.state, HallRequestState::Requested);
+    }
+
+    #[test]
+    fn orphaned_calls_are_taken_over_without_master() {
+        let mut state = SystemState::new("heis-1".to_string(), 4);
+        state
+            .elevators
+            .insert("heis-1".to_string(), idle_elevator(0));
+        state.hall_requests[0].up.press(HallRequestState::Requested);
+        state.hall_requests[1]
+            .up
+            .press(HallRequestState::Assigned("heis-2".to_string()));
+        state.hall_requests[2]
+            .down
+            .press(HallRequestState::Assigned("heis-3".to_string()));
+        state.hall_requests[3]
+            .down
+            .press(HallRequestState::Assigned("heis-1".to_string()));
+        // En fullført bestilling tas ikke opp igjen
+        state.hall_requests[2].up.press(HallRequestState::Requested);
+        state.hall_requests[2].up.complete();
+
+        let alive_elevators = HashSet::from(["heis-2".to_string()]);
+        assert!(state.take_over_orphaned_calls(&alive_elevators));
+
+        assert!(state.hall_requests[0].up.is_assigned_to("heis-1"));
+        assert!(state.hall_requests[1].up.is_assigned_to("heis-2"));
+        assert!(state.hall_requests[2].down.is_assigned_to("heis-1"));
+        assert!(state.hall_requests[3].down.is_assigned_to("heis-1"));
+        assert!(!state.hall_requests[2].up.is_active());
+
+        let requests = state.requests_for_local_elevator();
+        assert!(requests[0].hall_up && requests[2].hall_down && requests[3].hall_down);
+        assert!(!requests[1].hall_up && !requests[2].hall_up);
+
+        // Ingenting nytt å ta over andre gang
+        assert!(!state.take_over_orphaned_calls(&alive_elevators));
+    }
+
+    #[test]
+    fn local_calls_are_kept_until_master_has_them() {
+        let mut local_state = SystemState::new("heis-1".to_string(), 4);
+        local_state.hall_requests[1]
+            .up
+            .press(HallRequestState::Requested);
+        local_state.hall_requests[2]
+            .down
+            .press(HallRequestState::Requested);
+
+        // Master har fått med seg bestillingen i etasje 2, men ikke den i etasje 1
+        let mut master_state = SystemState::new("master".to_string(), 4);
+        master_state.hall_requests[2]
+            .down
+            .press(HallRequestState::Assigned("heis-2".to_string()));
+
+        let mut received = master_state.clone();
+        assert!(received.merge_hall_requests(&local_state.hall_requests));
+        assert_eq!(
+            received.hall_requests[1].up.state,
+            HallRequestState::Requested
+        );
+        assert!(received.hall_requests[2].down.is_assigned_to("heis-2"));
+
+        // Neste tilstand fra master har med bestillingen, så den er bekreftet
+        master_state.hall_requests[1].up = received.hall_requests[1].up.clone();
+        master_state.hall_requests[1].up.state = HallRequestState::Assigned("heis-1".to_string());
+        let mut received = master_state.clone();
+        assert!(!received.merge_hall_requests(&local_state.hall_requests));
+        assert!(received.hall_requests[1].up.is_assigned_to("heis-1"));
+    }
+
+    // Endringene én node gjør på etasjebestillingene, slik de sendes til de andre nodene
+    fn recorded_calls() -> Vec<(usize, bool, HallCall)> {
+        let mut state = SystemState::new("heis-1".to_string(), 4);
+        let mut calls = Vec::new();
+        let mut record = |state: &SystemState, floor: usize, up: bool| {
+            let request = &state.hall_requests[floor];
+            calls.push((
+                floor,
+                up,
+                if up {
+                    request.up.clone()
+                } else {
+                    request.down.clone()
+                },
+            ));
+        };
+
+        state.hall_requests[1].up.press(HallRequestState::Requested);
+        record(&state, 1, true);
+        state.hall_requests[2]
+            .down
+            .press(HallRequestState::Requested);
+        record(&state, 2, false);
+        state.hall_requests[1].up.state = HallRequestState::Assigned("heis-2".to_string());
+        state.hall_requests[1].up.press(HallRequestState::Requested); // Allerede aktiv
+        record(&state, 1, true);
+        state.hall_requests[1].up.complete();
+        record(&state, 1, true);
+        state.hall_requests[1].up.press(HallRequestState::Requested);
+        record(&state, 1, true);
+        state.hall_requests[2].down.complete();
+        state.hall_requests[2].down.complete(); // Allerede fullført
+        record(&state, 2, false);
+
+        calls
+    }
+
+    fn receive(calls: &[&(usize, bool, HallCall)]) -> Vec<HallRequest> {
+        let mut state = SystemState::new("heis-3".to_string(), 4);
+        for (floor, up, call) in calls {
+            let request = &mut state.hall_requests[*floor];
+            if *up {
+                request.up.merge(call);
+            } else {
+                request.down.merge(call);
+            }
+        }
+        state.hall_requests
+    }
+
+    // Alle rekkefølger av `items`
+    fn permutations<T: Copy>(items: &[T]) -> Vec<Vec<T>> {
+        if items.is_empty() {
+            return vec![Vec::new()];
+        }
+
+        let mut result = Vec::new();
+        for index in 0..items.len() {
+            let mut rest = items.to_vec();
+            let first = rest.remove(index);
+            for mut permutation in permutations(&rest) {
+                permutation.insert(0, first);
+                result.push(permutation);
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn hall_calls_converge_in_any_order() {
+        let calls = recorded_calls();
+        let in_order: Vec<_> = calls.iter().collect();
+        let expected = receive(&in_order);
+
+        assert!(expected[1].up.is_active());
+        assert_eq!(expected[1].up.state, HallRequestState::Requested);
+        assert!(!expected[2].down.is_active());
+
+        for permutation in permutations(&in_order) {
+            assert_eq!(
+                receive(&permutation),
+                expected,
+                "Rekkefølge: {permutation:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn duplicated_hall_calls_change_nothing() {
+        let calls = recorded_calls();
+        let in_order: Vec<_> = calls.iter().collect();
+        let expected = receive(&in_order);
+
+        // Hver melding to ganger, både rett etter hverandre og etter at alt er mottatt
+        let back_to_back: Vec<_> = in_order.iter().flat_map(|call| [*call, *call]).collect();
+        assert_eq!(receive(&back_to_back), expected);
+
+        let mut twice = in_order.clone();
+        twice.extend(in_order.iter().rev());
+        assert_eq!(receive(&twice), expected);
+
+        let mut state = SystemState::new("heis-3".to_string(), 4);
+        state.hall_requests = expected.clone();
+        assert!(!state.merge_hall_requests(&expected));
+    }
+}
@@ -1,5 +1,5 @@
 use crossbeam_channel as cbc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread::{sleep, spawn};
 use std::time::Duration;
@@ -10,6 +10,8 @@ pub struct Timer {
     timeout_channel_rx: cbc::Receiver<()>,
     duration: Duration,
     is_active: Arc<AtomicBool>,
+    // Økes hver gang timeren stoppes, slik at tråder fra tidligere starter ikke gir timeout
+    generation: Arc<AtomicUsize>,
 }
 
 impl Timer {
@@ -21,6 +23,7 @@ impl Timer {
             timeout_channel_tx,
             duration,
             is_active: Arc::new(AtomicBool::new(false)),
+            generation: Arc::new(AtomicUsize::new(0)),
         }
     }
 
@@ -34,16 +37,36 @@ impl Timer {
         }
 
         let timeout_channel_tx = self.timeout_channel_tx.clone();
-        let duration = self.duration.clone();
+        let duration = self.duration;
         let is_active = Arc::clone(&self.is_active);
+        let generation = Arc::clone(&self.generation);
+        let started_generation = generation.load(Ordering::Relaxed);
 
         spawn(move || {
             sleep(duration);
+
+            if generation.load(Ordering::Relaxed) != started_generation {
+                return;
+            }
+
             is_active.store(false, Ordering::Relaxed);
-            timeout_channel_tx.send(()).unwrap();
+            // Feiler bare dersom timeren er sluppet mens den gikk
+            let _ = timeout_channel_tx.send(());
         });
     }
 
+    /// Stopper timeren uten at den gir timeout. Gjør ingenting om timeren ikke er startet.
+    pub fn stop(&mut self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        self.is_active.store(false, Ordering::Relaxed);
+    }
+
+    /// Starter timeren på nytt med full varighet, selv om den allerede er startet.
+    pub fn restart(&mut self) {
+        self.stop();
+        self.start();
+    }
+
     pub fn timeout_channel(&self) -> &cbc::Receiver<()> {
         &self.timeout_channel_rx
     }
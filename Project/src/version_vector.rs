@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Hvordan to versjoner av tilstanden henger sammen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Equal,
+    /// Den andre tilstanden har sett alt denne har sett, og mer
+    Before,
+    /// Denne tilstanden har sett alt den andre har sett, og mer
+    After,
+    /// Begge har endringer den andre ikke har sett
+    Concurrent,
+}
+
+/// Teller hvor mange endringer hver node har gjort på tilstanden. I motsetning til én felles
+/// teller kan den skille en utdatert tilstand fra en som er endret samtidig et annet sted.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+impl VersionVector {
+    /// Registrerer en ny endring gjort av `node`. Telleren hopper fram til klokka i millisekunder,
+    /// slik at en node som har mistet backupen sin ikke starter bak det de andre har sett fra den.
+    pub fn increment(&mut self, node: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        let version = self.0.entry(node.to_string()).or_insert(0);
+        *version = (*version + 1).max(now);
+    }
+
+    pub fn compare(&self, other: &VersionVector) -> Causality {
+        let nodes = self.0.keys().chain(other.0.keys());
+
+        let (mut is_behind, mut is_ahead) = (false, false);
+        for node in nodes {
+            let (mine, theirs) = (self.get(node), other.get(node));
+            is_behind |= mine < theirs;
+            is_ahead |= mine > theirs;
+        }
+
+        match (is_behind, is_ahead) {
+            (false, false) => Causality::Equal,
+            (true, false) => Causality::Before,
+            (false, true) => Causality::After,
+            (true, true) => Causality::Concurrent,
+        }
+    }
+
+    /// Tar med endringene fra `other`, slik at resultatet har sett alt begge har sett.
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (node, version) in &other.0 {
+            let entry = self.0.entry(node.clone()).or_insert(0);
+            *entry = (*entry).max(*version);
+        }
+    }
+
+    /// Registrerer at endringene til `node` fram til `version` er sett.
+    pub fn observe(&mut self, node: &str, version: u64) {
+        let entry = self.0.entry(node.to_string()).or_insert(0);
+        *entry = (*entry).max(version);
+    }
+
+    pub fn get(&self, node: &str) -> u64 {
+        self.0.get(node).copied().unwrap_or(0)
+    }
+}
+
+impl fmt::Display for VersionVector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let versions: Vec<String> = self
+            .0
+            .iter()
+            .map(|(node, version)| format!("{node}: {version}"))
+            .collect();
+
+        write!(f, "{{{}}}", versions.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vector(versions: &[(&str, u64)]) -> VersionVector {
+        VersionVector(
+            versions
+                .iter()
+                .map(|(node, version)| (node.to_string(), *version))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn compare_orders_versions() {
+        let a = vector(&[("heis-1", 2), ("heis-2", 1)]);
+
+        assert_eq!(a.compare(&a.clone()), Causality::Equal);
+        assert_eq!(
+            VersionVector::default().compare(&VersionVector::default()),
+            Causality::Equal
+        );
+
+        let newer = vector(&[("heis-1", 3), ("heis-2", 1)]);
+        assert_eq!(a.compare(&newer), Causality::Before);
+        assert_eq!(newer.compare(&a), Causality::After);
+
+        // En node som mangler regnes som versjon 0
+        let with_new_node = vector(&[("heis-1", 2), ("heis-2", 1), ("heis-3", 1)]);
+        assert_eq!(a.compare(&with_new_node), Causality::Before);
+        assert_eq!(with_new_node.compare(&a), Causality::After);
+        assert_eq!(VersionVector::default().compare(&a), Causality::Before);
+
+        let other = vector(&[("heis-1", 1), ("heis-2", 2)]);
+        assert_eq!(a.compare(&other), Causality::Concurrent);
+        assert_eq!(other.compare(&a), Causality::Concurrent);
+    }
+
+    #[test]
+    fn merge_has_seen_both() {
+        let mut a = vector(&[("heis-1", 2), ("heis-2", 1)]);
+        let b = vector(&[("heis-1", 1), ("heis-3", 4)]);
+        assert_eq!(a.compare(&b), Causality::Concurrent);
+
+        let original = a.clone();
+        a.merge(&b);
+        assert_eq!(a, vector(&[("heis-1", 2), ("heis-2", 1), ("heis-3", 4)]));
+        assert_eq!(original.compare(&a), Causality::Before);
+        assert_eq!(b.compare(&a), Causality::Before);
+    }
+
+    #[test]
+    fn increment_moves_ahead() {
+        let mut a = vector(&[("heis-1", 5)]);
+        let before = a.clone();
+
+        a.increment("heis-1");
+        assert!(a.get("heis-1") > 5);
+        assert_eq!(before.compare(&a), Causality::Before);
+
+        // En node som starter uten backup havner likevel foran det den sendte før
+        let mut restarted = VersionVector::default();
+        restarted.increment("heis-1");
+        assert!(restarted.get("heis-1") > before.get("heis-1"));
+    }
+}